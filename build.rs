@@ -0,0 +1,66 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// drift's ErrorCode is an anchor #[error_code] enum: fieldless variants numbered in declaration
+// order from ERROR_CODE_OFFSET -- generate a u32 -> ErrorCode match from the program source
+const DRIFT_ERROR_PATH: &str = "protocol-v2/programs/drift/src/error.rs";
+
+// every line of the enum is a #[msg(..)] attribute, a comment or a `Variant,` -- anything else
+// (eg. an explicit discriminant) would shift the numbering so the build fails instead
+fn get_error_code_variants(source: &str) -> Result<Vec<String>, String> {
+    let mut variants = vec![];
+    let mut in_enum = false;
+
+    for (i, line) in source.lines().enumerate() {
+        // trailing comments
+        let line = line.split("//").next().unwrap_or_default().trim();
+        if !in_enum {
+            in_enum = line.starts_with("pub enum ErrorCode");
+            continue;
+        }
+        if line.starts_with('}') {
+            if variants.is_empty() {
+                return Err("ErrorCode has no variants".to_string());
+            }
+            return Ok(variants);
+        }
+        if line.is_empty() || line.starts_with("#[") {
+            continue;
+        }
+
+        let variant = line.trim_end_matches(',');
+        let is_identifier = variant.starts_with(|c: char| c.is_ascii_alphabetic())
+            && variant.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !is_identifier {
+            return Err(format!("line {}: cant parse ErrorCode variant `{}`", i + 1, line));
+        }
+        variants.push(variant.to_string());
+    }
+
+    Err("pub enum ErrorCode { .. } not found".to_string())
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", DRIFT_ERROR_PATH);
+
+    let source = fs::read_to_string(DRIFT_ERROR_PATH)
+        .expect("protocol-v2 submodule is missing -- run git submodule update --init");
+    let variants = get_error_code_variants(&source).unwrap_or_else(|e| panic!("{}: {}", DRIFT_ERROR_PATH, e));
+
+    let mut arms = String::new();
+    for (i, variant) in variants.iter().enumerate() {
+        arms.push_str(&format!("        {} => Some(ErrorCode::{}),\n", i, variant));
+    }
+
+    let generated = format!(
+        "// generated by build.rs from {}\n\
+         pub fn error_code_from_index(index: u32) -> Option<ErrorCode> {{\n    \
+             match index {{\n{}        _ => None,\n    }}\n\
+         }}\n",
+        DRIFT_ERROR_PATH, arms,
+    );
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("error_codes.rs"), generated).unwrap();
+}
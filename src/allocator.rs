@@ -0,0 +1,204 @@
+use std::str::FromStr;
+
+use drift::error::DriftResult;
+use drift::math::casting::Cast;
+use drift::math::constants::BASE_PRECISION;
+use drift::math::safe_math::SafeMath;
+
+// perp:spot pair to hold an arb on, eg. "0:1"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MarketPair {
+    pub perp_market_index: u16,
+    pub spot_market_index: u16,
+}
+
+impl FromStr for MarketPair {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (perp, spot) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <perp index>:<spot index>, got {}", s))?;
+        Ok(MarketPair {
+            perp_market_index: perp.trim().parse().map_err(|_| format!("invalid perp market index {}", perp))?,
+            spot_market_index: spot.trim().parse().map_err(|_| format!("invalid spot market index {}", spot))?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocationPolicy {
+    // same capital for every market we enter
+    EqualWeight,
+    // proportional to each market's net carry
+    ProportionalToCarry,
+    // proportional to net carry but no market gets more than the cap (QUOTE_PRECISION)
+    // -- whatever gets capped off is spread over the other markets
+    Capped(u128),
+}
+
+impl AllocationPolicy {
+    // "equal" | "carry" | "capped" (capped needs a per market cap)
+    pub fn from_name(name: &str, max_market_allocation: Option<u128>) -> Option<Self> {
+        match (name, max_market_allocation) {
+            ("equal", _) => Some(AllocationPolicy::EqualWeight),
+            ("carry", _) => Some(AllocationPolicy::ProportionalToCarry),
+            ("capped", Some(cap)) => Some(AllocationPolicy::Capped(cap)),
+            _ => None,
+        }
+    }
+}
+
+// splits capital across markets by their net carry -- markets with no carry get nothing
+pub fn allocate(net_carries: &[i128], capital: u128, policy: AllocationPolicy) -> DriftResult<Vec<u128>> {
+    let weights: Vec<u128> = net_carries
+        .iter()
+        .map(|carry| match policy {
+            AllocationPolicy::EqualWeight => u128::from(*carry > 0),
+            _ => (*carry).max(0).unsigned_abs(),
+        })
+        .collect();
+    let cap = match policy {
+        AllocationPolicy::Capped(cap) => Some(cap),
+        _ => None,
+    };
+
+    let mut allocations = vec![0_u128; weights.len()];
+    let mut capped = vec![false; weights.len()];
+    let mut remaining = capital;
+
+    loop {
+        let total_weight: u128 = weights
+            .iter()
+            .zip(capped.iter())
+            .filter(|(_, is_capped)| !**is_capped)
+            .map(|(weight, _)| *weight)
+            .sum();
+        if total_weight == 0 || remaining == 0 {
+            break;
+        }
+
+        let mut newly_capped = false;
+        for (i, weight) in weights.iter().enumerate() {
+            if capped[i] || *weight == 0 {
+                continue;
+            }
+
+            allocations[i] = remaining.safe_mul(*weight)?.safe_div(total_weight)?;
+            if let Some(cap) = cap {
+                if allocations[i] > cap {
+                    allocations[i] = cap;
+                    capped[i] = true;
+                    newly_capped = true;
+                }
+            }
+        }
+
+        if !newly_capped {
+            break;
+        }
+
+        // redistribute what the capped markets couldnt take
+        let capped_total: u128 = allocations
+            .iter()
+            .zip(capped.iter())
+            .filter(|(_, is_capped)| **is_capped)
+            .map(|(allocation, _)| *allocation)
+            .sum();
+        remaining = capital.saturating_sub(capped_total);
+    }
+
+    Ok(allocations)
+}
+
+// QUOTE_PRECISION notional -> BASE_PRECISION amount at a PRICE_PRECISION price
+pub fn notional_to_base(notional: u128, price: u128) -> DriftResult<u64> {
+    if price == 0 {
+        return Ok(0);
+    }
+
+    notional
+        .safe_mul(BASE_PRECISION)?
+        .safe_div(price)?
+        .cast()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use drift::math::constants::PRICE_PRECISION;
+
+    #[test]
+    fn equal_split_across_markets_with_carry() {
+        assert_eq!(allocate(&[5, 0, 10], 300, AllocationPolicy::EqualWeight).unwrap(), vec![150, 0, 150]);
+    }
+
+    #[test]
+    fn carry_weighted_split() {
+        assert_eq!(allocate(&[1, 3], 400, AllocationPolicy::ProportionalToCarry).unwrap(), vec![100, 300]);
+        assert_eq!(allocate(&[1, -3, 3], 400, AllocationPolicy::ProportionalToCarry).unwrap(), vec![100, 0, 300]);
+    }
+
+    #[test]
+    fn capped_excess_goes_to_uncapped_markets() {
+        // 150 / 150 / 300 -> the last is capped at 250, its 50 is split over the others
+        assert_eq!(allocate(&[1, 1, 2], 600, AllocationPolicy::Capped(250)).unwrap(), vec![175, 175, 250]);
+        // cap that doesnt bind is the same as carry weighted
+        assert_eq!(allocate(&[1, 3], 400, AllocationPolicy::Capped(300)).unwrap(), vec![100, 300]);
+    }
+
+    #[test]
+    fn all_capped_leaves_the_rest_unallocated() {
+        let allocations = allocate(&[1, 3], 400, AllocationPolicy::Capped(100)).unwrap();
+        assert_eq!(allocations, vec![100, 100]);
+        assert_eq!(allocations.iter().sum::<u128>(), 200);
+    }
+
+    #[test]
+    fn no_carry_gets_nothing() {
+        for policy in [
+            AllocationPolicy::EqualWeight,
+            AllocationPolicy::ProportionalToCarry,
+            AllocationPolicy::Capped(100),
+        ] {
+            assert_eq!(allocate(&[0, -1, 0], 300, policy).unwrap(), vec![0, 0, 0]);
+            assert_eq!(allocate(&[1, 2], 0, policy).unwrap(), vec![0, 0]);
+            assert_eq!(allocate(&[], 300, policy).unwrap(), Vec::<u128>::new());
+        }
+    }
+
+    #[test]
+    fn parses_market_pairs() {
+        let pair = |perp_market_index, spot_market_index| MarketPair {
+            perp_market_index,
+            spot_market_index,
+        };
+        assert_eq!("0:1".parse::<MarketPair>(), Ok(pair(0, 1)));
+        assert_eq!(" 2 : 3 ".parse::<MarketPair>(), Ok(pair(2, 3)));
+
+        assert!("0".parse::<MarketPair>().is_err());
+        assert!("".parse::<MarketPair>().is_err());
+        assert!("x:1".parse::<MarketPair>().is_err());
+        assert!("0:y".parse::<MarketPair>().is_err());
+        assert!("0:1:2".parse::<MarketPair>().is_err());
+        assert!("-1:1".parse::<MarketPair>().is_err());
+        assert!("0:70000".parse::<MarketPair>().is_err());
+    }
+
+    #[test]
+    fn parses_policy_names() {
+        assert_eq!(AllocationPolicy::from_name("equal", None), Some(AllocationPolicy::EqualWeight));
+        assert_eq!(AllocationPolicy::from_name("carry", Some(1)), Some(AllocationPolicy::ProportionalToCarry));
+        assert_eq!(AllocationPolicy::from_name("capped", Some(1)), Some(AllocationPolicy::Capped(1)));
+        assert_eq!(AllocationPolicy::from_name("capped", None), None);
+        assert_eq!(AllocationPolicy::from_name("other", Some(1)), None);
+    }
+
+    #[test]
+    fn notional_converts_at_price() {
+        // 150 usd at 100
+        assert_eq!(notional_to_base(150_000_000, 100 * PRICE_PRECISION).unwrap(), 3 * BASE_PRECISION as u64 / 2);
+        assert_eq!(notional_to_base(150_000_000, 0).unwrap(), 0);
+    }
+}
@@ -0,0 +1,371 @@
+use drift::controller::position::PositionDirection;
+use drift::math::constants::{BASE_PRECISION_I128, ONE_YEAR, PERCENTAGE_PRECISION};
+use drift::state::perp_market::{PerpMarket, AMM};
+use drift::state::spot_market::{SpotBalanceType, SpotMarket};
+use drift::state::user::MarketType;
+
+use crate::constants::*;
+use crate::engine::ArbAction;
+use crate::planner::{plan_rebalance_from, RebalanceTarget};
+use crate::policy::{get_held_direction, PolicyConfig, PolicyInput, PolicyState};
+use crate::math::gross_apr;
+use crate::profit::{compute_profitability, ProfitabilityConfig, TradeCosts};
+use crate::recorder::RateSample;
+
+// APR % in 1e9 precision -> fraction
+const APR_PRECISION: i128 = 100 * 1_000_000_000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BacktestConfig {
+    pub perp_market_index: u16,
+    pub spot_market_index: u16,
+    // base precision (1e9)
+    pub target_position_size: u64,
+    // entry + exit costs are amortized over this (the entry threshold is policy.entry_net_apr)
+    pub holding_horizon_seconds: i64,
+    pub policy: PolicyConfig,
+    // taker fee + slippage actually paid per leg (PERCENTAGE_PRECISION)
+    pub perp_fee: u128,
+    pub spot_fee: u128,
+    pub perp_slippage: u128,
+    pub spot_slippage: u128,
+}
+
+// QUOTE_PRECISION
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BacktestResult {
+    // carry + price pnl - costs
+    pub pnl: i128,
+    // funding + deposit earned - borrow paid
+    pub carry: i128,
+    // legs not offsetting each other
+    pub price_pnl: i128,
+    // fees + slippage
+    pub costs: i128,
+    // notional traded
+    pub turnover: u128,
+    pub max_drawdown: i128,
+    pub trades: u64,
+    pub samples: usize,
+}
+
+// what each leg earns (+) or pays (-) over the interval (QUOTE_PRECISION)
+fn compute_carry(sample: &RateSample, perp: i64, spot: i64, seconds: i64) -> i128 {
+    let price = sample.oracle_price as i128;
+    let perp_notional = perp as i128 * price / BASE_PRECISION_I128;
+    let spot_notional = spot as i128 * price / BASE_PRECISION_I128;
+
+    // funding is paid to the funding direction's side
+    let funding_apr = match sample.funding_direction {
+        PositionDirection::Long => sample.funding_apr as i128,
+        PositionDirection::Short => -(sample.funding_apr as i128),
+    };
+    let spot_apr = if spot >= 0 { sample.deposit_apr as i128 } else { sample.borrow_apr as i128 };
+
+    let apr_notional = perp_notional * funding_apr + spot_notional * spot_apr;
+    apr_notional * seconds as i128 / (ONE_YEAR as i128 * APR_PRECISION)
+}
+
+// legs not offsetting each other move with the price over the interval (QUOTE_PRECISION)
+fn compute_price_pnl(last: &RateSample, sample: &RateSample, perp: i64, spot: i64) -> i128 {
+    (perp as i128 + spot as i128) * (sample.oracle_price as i128 - last.oracle_price as i128) / BASE_PRECISION_I128
+}
+
+// replays recorded samples of the pair through the live decision (PolicyState) and
+// planning (plan_rebalance_from) logic -- decisions use the recorded predicted funding_apr,
+// the live --funding-horizon-hours blend with the last 24h avg isnt modelled
+pub fn run_backtest(samples: &[RateSample], config: &BacktestConfig) -> Result<BacktestResult> {
+    // no rounding so the planner trades exactly what the decision asks for + 9 decimals so the
+    // spot leg's token amount is its base amount
+    let perp_market = PerpMarket {
+        market_index: config.perp_market_index,
+        amm: AMM {
+            order_step_size: 1,
+            ..AMM::default()
+        },
+        ..PerpMarket::default()
+    };
+    let spot_market = SpotMarket {
+        market_index: config.spot_market_index,
+        decimals: 9,
+        order_step_size: 1,
+        ..SpotMarket::default()
+    };
+    let profitability_config = ProfitabilityConfig {
+        holding_horizon_seconds: config.holding_horizon_seconds,
+        spot_slippage: config.spot_slippage,
+    };
+    let costs = TradeCosts {
+        perp_fee: config.perp_fee,
+        spot_fee: config.spot_fee,
+        perp_slippage: config.perp_slippage,
+        spot_slippage: config.spot_slippage,
+    };
+
+    let mut result = BacktestResult::default();
+    let mut perp: i64 = 0;
+    let mut spot: i64 = 0;
+    let mut equity_peak: i128 = 0;
+    let mut policy_state = PolicyState::default();
+    let mut last_sample: Option<&RateSample> = None;
+
+    let pair_samples = samples.iter().filter(|sample| {
+        sample.perp_market_index == config.perp_market_index && sample.spot_market_index == config.spot_market_index
+    });
+    for sample in pair_samples {
+        // positions held since the last sample earn/pay its rates + move with the price
+        if let Some(last) = last_sample {
+            let seconds = (sample.ts - last.ts).max(0);
+            result.carry += compute_carry(last, perp, spot, seconds);
+            result.price_pnl += compute_price_pnl(last, sample, perp, spot);
+        }
+
+        let gross_apr = gross_apr(sample.funding_apr as i128, sample.funding_direction, sample.borrow_apr, sample.deposit_apr)?;
+        let delta = gross_apr.max(0) as u128;
+        let profitability = compute_profitability(delta, costs, &profitability_config, config.policy.entry_net_apr)?;
+
+        policy_state.sync(get_held_direction(perp as i128, spot as i128));
+        let action = policy_state.decide(&config.policy, &PolicyInput {
+            ts: sample.ts,
+            direction: sample.funding_direction,
+            gross_apr,
+            should_enter: profitability.should_enter,
+            close: false,
+        });
+
+        if action != ArbAction::Hold {
+            let (perp_direction, spot_balance_type) = match sample.funding_direction {
+                PositionDirection::Long => (PositionDirection::Long, SpotBalanceType::Borrow),
+                PositionDirection::Short => (PositionDirection::Short, SpotBalanceType::Deposit),
+            };
+            let target = RebalanceTarget {
+                base_asset_amount: config.target_position_size,
+                perp_direction,
+                spot_balance_type,
+                close: action == ArbAction::Close,
+            };
+
+            for intent in plan_rebalance_from(perp as i128, spot as i128, &perp_market, &spot_market, &target)? {
+                let amount = intent.base_asset_amount as i64;
+                let signed_amount = match intent.direction {
+                    PositionDirection::Long => amount,
+                    PositionDirection::Short => -amount,
+                };
+                let (fee, slippage) = match intent.market_type {
+                    MarketType::Perp => {
+                        perp += signed_amount;
+                        (costs.perp_fee, costs.perp_slippage)
+                    }
+                    MarketType::Spot => {
+                        spot += signed_amount;
+                        (costs.spot_fee, costs.spot_slippage)
+                    }
+                };
+
+                let notional = (amount as i128 * sample.oracle_price as i128 / BASE_PRECISION_I128).unsigned_abs();
+                result.turnover += notional;
+                result.costs += (notional * (fee + slippage) / PERCENTAGE_PRECISION) as i128;
+                result.trades += 1;
+            }
+        }
+
+        result.pnl = result.carry + result.price_pnl - result.costs;
+        equity_peak = equity_peak.max(result.pnl);
+        result.max_drawdown = result.max_drawdown.max(equity_peak - result.pnl);
+        result.samples += 1;
+        last_sample = Some(sample);
+    }
+
+    Ok(result)
+}
+
+pub fn print_backtest(results: &[(BacktestConfig, BacktestResult)]) {
+    let usdc = |x: i128| x as f64 / 1e6;
+    println!(
+        "{:>10} {:>8} {:>8} {:>12} {:>12} {:>12} {:>12} {:>12} {:>12} {:>7}",
+        "min apr %", "horizon", "samples", "pnl", "carry", "price pnl", "costs", "turnover", "max dd", "trades"
+    );
+    for (config, result) in results.iter() {
+        println!(
+            "{:>10.2} {:>7.1}d {:>8} {:>12.2} {:>12.2} {:>12.2} {:>12.2} {:>12.2} {:>12.2} {:>7}",
+            config.policy.entry_net_apr as f64 / 1e9,
+            config.holding_horizon_seconds as f64 / 86_400.0,
+            result.samples,
+            usdc(result.pnl),
+            usdc(result.carry),
+            usdc(result.price_pnl),
+            usdc(result.costs),
+            result.turnover as f64 / 1e6,
+            usdc(result.max_drawdown),
+            result.trades,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use drift::controller::position::PositionDirection::{Long, Short};
+    use drift::math::constants::{BASE_PRECISION_U64, PRICE_PRECISION_I64, QUOTE_PRECISION};
+
+    const ONE_PERCENT: u128 = 1_000_000_000;
+    const ONE: i64 = BASE_PRECISION_U64 as i64;
+    const PRICE: i64 = 100 * PRICE_PRECISION_I64;
+    // 1% of a year so 1% APR on 100 USDC earns 0.01 USDC
+    const STEP: i64 = ONE_YEAR as i64 / 100;
+
+    // 4% borrow, 1% deposit
+    fn sample(ts: i64, funding_apr: u128, funding_direction: PositionDirection, oracle_price: i64) -> RateSample {
+        RateSample {
+            ts,
+            slot: 0,
+            perp_market_index: 0,
+            spot_market_index: 1,
+            symbol: "SOL".to_string(),
+            funding_apr,
+            funding_direction,
+            last_funding_apr: 0,
+            last_24h_avg_funding_apr: 0,
+            borrow_apr: 4 * ONE_PERCENT,
+            deposit_apr: ONE_PERCENT,
+            utilization: 0,
+            oracle_price,
+            oracle_twap: oracle_price,
+            mark_twap: oracle_price as u64,
+            net_apr: 0,
+            action: String::new(),
+        }
+    }
+
+    // 0.1% fee + 0.05% slippage per leg -> 0.6% round trip, amortized over a year
+    fn config() -> BacktestConfig {
+        BacktestConfig {
+            perp_market_index: 0,
+            spot_market_index: 1,
+            target_position_size: BASE_PRECISION_U64,
+            holding_horizon_seconds: ONE_YEAR as i64,
+            policy: PolicyConfig {
+                entry_net_apr: 2 * ONE_PERCENT,
+                exit_apr: ONE_PERCENT as i128,
+                min_hold_seconds: 0,
+                flip_confirmations: 1,
+            },
+            perp_fee: 1_000,
+            spot_fee: 1_000,
+            perp_slippage: 500,
+            spot_slippage: 500,
+        }
+    }
+
+    #[test]
+    fn carry_follows_the_funding_side() {
+        let long_funding = sample(0, 10 * ONE_PERCENT, Long, PRICE);
+        // long perp receives 10%, borrowed spot pays 4% of 100 USDC
+        assert_eq!(compute_carry(&long_funding, ONE, -ONE, ONE_YEAR as i64), 6 * QUOTE_PRECISION as i128);
+        // short perp pays 10%, deposited spot earns 1%
+        assert_eq!(compute_carry(&long_funding, -ONE, ONE, ONE_YEAR as i64), -9 * QUOTE_PRECISION as i128);
+
+        let short_funding = sample(0, 10 * ONE_PERCENT, Short, PRICE);
+        assert_eq!(compute_carry(&short_funding, -ONE, ONE, ONE_YEAR as i64), 11 * QUOTE_PRECISION as i128);
+        assert_eq!(compute_carry(&short_funding, ONE, -ONE, ONE_YEAR as i64), -14 * QUOTE_PRECISION as i128);
+
+        // pro rata over the interval
+        assert_eq!(compute_carry(&long_funding, ONE, -ONE, STEP), 60_000);
+        assert_eq!(compute_carry(&long_funding, 0, 0, STEP), 0);
+    }
+
+    #[test]
+    fn spot_leg_earns_deposit_or_pays_borrow() {
+        let no_funding = sample(0, 0, Long, PRICE);
+        assert_eq!(compute_carry(&no_funding, 0, ONE, ONE_YEAR as i64), QUOTE_PRECISION as i128);
+        assert_eq!(compute_carry(&no_funding, 0, -ONE, ONE_YEAR as i64), -4 * QUOTE_PRECISION as i128);
+    }
+
+    #[test]
+    fn only_unhedged_size_moves_with_the_price() {
+        let last = sample(0, 0, Long, PRICE);
+        let up = sample(STEP, 0, Long, 110 * PRICE_PRECISION_I64);
+        let down = sample(STEP, 0, Long, 90 * PRICE_PRECISION_I64);
+
+        assert_eq!(compute_price_pnl(&last, &up, ONE, -ONE), 0);
+        assert_eq!(compute_price_pnl(&last, &up, ONE, 0), 10 * QUOTE_PRECISION as i128);
+        assert_eq!(compute_price_pnl(&last, &up, ONE, -ONE / 2), 5 * QUOTE_PRECISION as i128);
+        assert_eq!(compute_price_pnl(&last, &down, -ONE, 0), 10 * QUOTE_PRECISION as i128);
+        assert_eq!(compute_price_pnl(&last, &down, 0, ONE), -10 * QUOTE_PRECISION as i128);
+    }
+
+    #[test]
+    fn open_hold_close_round_trip() {
+        let samples = [
+            // 10% - 4% borrow = 6% gross, 5.4% net -> open
+            sample(0, 10 * ONE_PERCENT, Long, PRICE),
+            // already at the target -> no orders
+            sample(STEP, 10 * ONE_PERCENT, Long, PRICE),
+            // 4% - 4% borrow = 0% gross at or below the 1% exit -> close
+            sample(2 * STEP, 4 * ONE_PERCENT, Long, PRICE),
+        ];
+        let result = run_backtest(&samples, &config()).unwrap();
+
+        // 2 legs x 100 USDC in + out, 0.15% of each
+        assert_eq!(result.trades, 4);
+        assert_eq!(result.turnover, 400 * QUOTE_PRECISION);
+        assert_eq!(result.costs, 600_000);
+        // 6% on 100 USDC for 2 steps
+        assert_eq!(result.carry, 120_000);
+        assert_eq!(result.price_pnl, 0);
+        assert_eq!(result.pnl, 120_000 - 600_000);
+        // peak 0 before trading, lowest after the exit costs
+        assert_eq!(result.max_drawdown, 480_000);
+        assert_eq!(result.samples, 3);
+    }
+
+    #[test]
+    fn short_funding_deposits_spot_and_stays_hedged() {
+        let samples = [
+            sample(0, 10 * ONE_PERCENT, Short, PRICE),
+            // +20% price -- short perp and deposited spot offset each other
+            sample(STEP, 10 * ONE_PERCENT, Short, 120 * PRICE_PRECISION_I64),
+        ];
+        let result = run_backtest(&samples, &config()).unwrap();
+
+        assert_eq!(result.trades, 2);
+        assert_eq!(result.turnover, 200 * QUOTE_PRECISION);
+        assert_eq!(result.costs, 300_000);
+        // 10% + 1% deposit on 100 USDC for a step
+        assert_eq!(result.carry, 110_000);
+        assert_eq!(result.price_pnl, 0);
+        assert_eq!(result.pnl, 110_000 - 300_000);
+        assert_eq!(result.max_drawdown, 300_000);
+    }
+
+    #[test]
+    fn below_the_entry_threshold_never_trades() {
+        // 6% - 4% borrow = 2% gross, 1.4% net
+        let samples = [sample(0, 6 * ONE_PERCENT, Long, PRICE), sample(STEP, 6 * ONE_PERCENT, Long, PRICE)];
+        let result = run_backtest(&samples, &config()).unwrap();
+        assert_eq!(result, BacktestResult { samples: 2, ..BacktestResult::default() });
+    }
+
+    #[test]
+    fn other_pairs_are_filtered_out() {
+        let pair = [
+            sample(0, 10 * ONE_PERCENT, Long, PRICE),
+            sample(STEP, 10 * ONE_PERCENT, Long, PRICE),
+            sample(2 * STEP, 4 * ONE_PERCENT, Long, PRICE),
+        ];
+
+        let other_perp = RateSample {
+            perp_market_index: 2,
+            ..sample(STEP / 2, 50 * ONE_PERCENT, Short, 3 * PRICE)
+        };
+        let other_spot = RateSample {
+            spot_market_index: 3,
+            ..sample(3 * STEP / 2, 0, Short, PRICE / 2)
+        };
+        let mixed = [pair[0].clone(), other_perp, pair[1].clone(), other_spot, pair[2].clone()];
+
+        assert_eq!(run_backtest(&mixed, &config()).unwrap(), run_backtest(&pair, &config()).unwrap());
+        assert_eq!(run_backtest(&mixed, &config()).unwrap().samples, 3);
+    }
+}
@@ -2,8 +2,12 @@ use anchor_client::solana_sdk::pubkey::Pubkey;
 use std::result;
 use std::str::FromStr;
 use anchor_client::solana_client::client_error::ClientError;
+use anchor_client::solana_sdk::transaction::TransactionError;
 use thiserror::Error;
 use anchor_client::anchor_lang::error::Error as AnchorError;
+use drift::error::ErrorCode;
+
+use crate::error_codes::describe_error_code;
 
 lazy_static! {
     pub static ref TOKEN_PROGRAM_ID: Pubkey = Pubkey::from_str("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA").unwrap();
@@ -17,6 +21,118 @@ pub enum DriftError {
     RpcError(#[from] ClientError),
     #[error("AnchorError {0}")]
     AnchorError(#[from] AnchorError),
+    #[error("perp/spot markets dont match: {0} {1}")]
+    MarketMismatch(String, String),
+    #[error("market {0} not found -- call refresh() first")]
+    MarketNotFound(Pubkey),
+    #[error("mismatch variant when cast to {0}")]
+    MarketTypeMismatch(&'static str),
+    #[error("state not loaded -- call refresh() first")]
+    StateNotLoaded,
+    #[error("oracle {0} is stale: {1}")]
+    OracleStale(Pubkey, String),
+    #[error("oracle {0} is invalid: {1}")]
+    OracleInvalid(Pubkey, String),
+    #[error("insufficient collateral: free collateral {free_collateral} margin requirement {margin_requirement}")]
+    InsufficientCollateral { free_collateral: u128, margin_requirement: u128 },
+    #[error("withdrawing {amount} exceeds the usdc deposit {deposit}")]
+    InsufficientDeposit { amount: u64, deposit: u128 },
+    #[error("withdrawing {amount} would leave health at {health} (min {min_health})")]
+    UnsafeWithdrawal { amount: u64, health: u128, min_health: u128 },
+    #[error("order rejected: {}", describe_error_code(*.0))]
+    OrderRejected(u32),
+    #[error("simulation failed: {0}")]
+    SimulationFailed(TransactionError),
+    #[error("market error: {0:?}")]
+    MarketError(ErrorCode),
+    #[error("oracle error: {0:?}")]
+    OracleError(ErrorCode),
+    #[error("collateral error: {0:?}")]
+    CollateralError(ErrorCode),
+    #[error("math error: {0:?}")]
+    MathError(ErrorCode),
+    #[error("failed to read keypair: {0}")]
+    KeypairError(String),
+    #[error("IoError {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("invalid record on line {0}: {1}")]
+    InvalidRecord(usize, String),
+}
+
+// drift's ErrorCode doesnt implement std::error::Error so #[from] cant be used
+// -- the market/oracle/collateral codes keep their category, the rest are math errors
+impl From<ErrorCode> for DriftError {
+    fn from(error_code: ErrorCode) -> Self {
+        match error_code {
+            ErrorCode::PerpMarketNotFound
+            | ErrorCode::SpotMarketNotFound
+            | ErrorCode::UnableToLoadPerpMarketAccount
+            | ErrorCode::UnableToLoadSpotMarketAccount => DriftError::MarketError(error_code),
+            ErrorCode::InvalidOracle | ErrorCode::OracleNotFound | ErrorCode::UnableToLoadOracle => {
+                DriftError::OracleError(error_code)
+            }
+            ErrorCode::InsufficientCollateral => DriftError::CollateralError(error_code),
+            _ => DriftError::MathError(error_code),
+        }
+    }
+}
+
+impl DriftError {
+    // process exit code per category (1 = panic, 2 = bad cli args)
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            DriftError::RpcError(_) => 3,
+            DriftError::AnchorError(_) => 4,
+            DriftError::MarketMismatch(..)
+            | DriftError::MarketNotFound(_)
+            | DriftError::MarketTypeMismatch(_)
+            | DriftError::StateNotLoaded
+            | DriftError::MarketError(_) => 5,
+            DriftError::OracleStale(..) | DriftError::OracleInvalid(..) | DriftError::OracleError(_) => 6,
+            DriftError::InsufficientCollateral { .. }
+            | DriftError::InsufficientDeposit { .. }
+            | DriftError::UnsafeWithdrawal { .. }
+            | DriftError::CollateralError(_) => 7,
+            DriftError::OrderRejected(_) | DriftError::SimulationFailed(_) => 8,
+            DriftError::MathError(_) => 9,
+            DriftError::KeypairError(_) => 10,
+            DriftError::IoError(_) | DriftError::InvalidRecord(..) => 11,
+        }
+    }
 }
 
 pub type Result<T> = result::Result<T, DriftError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn exit_codes_per_category() {
+        assert_eq!(DriftError::MarketMismatch("SOL-PERP".to_string(), "USDC".to_string()).exit_code(), 5);
+        assert_eq!(DriftError::MarketNotFound(Pubkey::default()).exit_code(), 5);
+        assert_eq!(DriftError::StateNotLoaded.exit_code(), 5);
+        assert_eq!(DriftError::OracleStale(Pubkey::default(), "slot delay".to_string()).exit_code(), 6);
+        assert_eq!(DriftError::OracleInvalid(Pubkey::default(), "zero price".to_string()).exit_code(), 6);
+        assert_eq!(DriftError::InsufficientCollateral { free_collateral: 0, margin_requirement: 1 }.exit_code(), 7);
+        assert_eq!(DriftError::InsufficientDeposit { amount: 2, deposit: 1 }.exit_code(), 7);
+        assert_eq!(DriftError::UnsafeWithdrawal { amount: 1, health: 10, min_health: 20 }.exit_code(), 7);
+        assert_eq!(DriftError::OrderRejected(6003).exit_code(), 8);
+        assert_eq!(DriftError::SimulationFailed(TransactionError::AccountNotFound).exit_code(), 8);
+        assert_eq!(DriftError::KeypairError("bad keypair".to_string()).exit_code(), 10);
+        assert_eq!(DriftError::IoError(std::io::Error::from(ErrorKind::NotFound)).exit_code(), 11);
+        assert_eq!(DriftError::InvalidRecord(2, "bad ts".to_string()).exit_code(), 11);
+    }
+
+    #[test]
+    fn drift_error_codes_keep_their_category() {
+        assert_eq!(DriftError::from(ErrorCode::PerpMarketNotFound).exit_code(), 5);
+        assert_eq!(DriftError::from(ErrorCode::SpotMarketNotFound).exit_code(), 5);
+        assert_eq!(DriftError::from(ErrorCode::InvalidOracle).exit_code(), 6);
+        assert_eq!(DriftError::from(ErrorCode::OracleNotFound).exit_code(), 6);
+        assert_eq!(DriftError::from(ErrorCode::InsufficientCollateral).exit_code(), 7);
+        assert_eq!(DriftError::from(ErrorCode::MathError).exit_code(), 9);
+        assert_eq!(DriftError::from(ErrorCode::CastingFailure).exit_code(), 9);
+    }
+}
@@ -0,0 +1,1095 @@
+use std::collections::HashMap;
+use std::ops::Mul;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use anchor_client::solana_client::rpc_client::RpcClient;
+use anchor_client::solana_sdk::instruction::{AccountMeta, Instruction};
+use anchor_client::solana_sdk::pubkey::Pubkey;
+use anchor_client::solana_sdk::signature::{Signature, Signer};
+use anchor_client::solana_sdk::{system_program, sysvar};
+
+use drift::controller::position::PositionDirection;
+use drift::instructions::OrderParams;
+use drift::math::constants::{BASE_PRECISION, BASE_PRECISION_U64, QUOTE_PRECISION};
+use drift::math::margin::MarginRequirementType;
+use drift::math::oracle::DriftAction;
+use drift::state::perp_market::PerpMarket;
+use drift::state::spot_market::{SpotMarket, SpotBalanceType};
+use drift::state::state::State;
+use drift::state::user::{OrderStatus, OrderType, MarketType, User};
+
+// anchor program ixs
+use drift::instruction as ix;
+use drift::accounts;
+
+use crate::address::*;
+use crate::allocator::{allocate, notional_to_base, AllocationPolicy, MarketPair};
+use crate::constants::*;
+use crate::health::{calculate_health, compute_delever_fraction, compute_health, print_health, Health};
+use crate::margin::{calculate_margin, get_account_snapshots, MarginInfo};
+use crate::math::{
+    check_oracle_validity, compute_funding_projection, compute_borrow_rate, compute_deposit_rate, compute_utilization,
+    get_perp_oracle_info, get_spot_oracle_info, gross_apr, print_funding_projection, FundingProjection,
+};
+use crate::policy::{get_held_direction, get_market_policy, MarketPolicy, PolicyConfig, PolicyInput, PolicyState};
+use crate::pricing::{get_perp_auction_prices, get_spot_auction_prices, AuctionConfig, AuctionPrices};
+use crate::planner::{compute_net_delta, get_current_legs, plan_delever, plan_rebalance, plan_reconcile, plan_unwind, OrderIntent, RebalanceTarget};
+use crate::recorder::{RateSample, Recorder};
+use crate::profit::{compute_profitability, estimate_trade_costs, print_profitability, Profitability, ProfitabilityConfig};
+use crate::sizing::{get_base_asset_amount, print_sizing, size_position, PositionSize, SizingConfig};
+use crate::tx::{build_instruction, simulate_transaction, send_transaction, print_simulation, SimulationReport};
+use crate::utils::*;
+
+// name of subaccounts created by initialize() (drift pads names with spaces)
+const USER_NAME: &[u8] = b"Funding Arb";
+
+// ~slot time on mainnet
+const SLOT_MS: u64 = 400;
+// slots to wait past the auction for fillers to land
+const AUCTION_BUFFER_SLOTS: u64 = 10;
+
+pub fn get_order_params(
+    order_type: OrderType,
+    market_type: MarketType,
+    direction: PositionDirection,
+    base_asset_amount: u64,
+    market_index: u16,
+    reduce_only: bool,
+    auction: Option<AuctionPrices>,
+) -> OrderParams {
+    // start = oracle, end = swap impact (the end price is also the limit price)
+    let price = auction.map_or(0, |auction| auction.end_price.unsigned_abs());
+
+    OrderParams {
+        order_type,
+        market_type,
+        direction,
+        base_asset_amount,
+        market_index,
+        reduce_only,
+        user_order_id: 0,
+        price,
+        post_only: false,
+        immediate_or_cancel: false,
+        trigger_price: None,
+        trigger_condition: drift::state::user::OrderTriggerCondition::Above,
+        oracle_price_offset: None,
+        auction_duration: auction.map(|auction| auction.duration),
+        max_ts: None,
+        auction_start_price: auction.map(|auction| auction.start_price),
+        auction_end_price: auction.map(|auction| auction.end_price),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ArbConfig {
+    // drift program (PROGRAM_ID on mainnet/devnet)
+    pub program_id: Pubkey,
+    pub subaccount_id: u16,
+    // perp to long/short for funding
+    pub perp_market_index: u16,
+    // spot to long/short for delta-neutral position
+    pub spot_market_index: u16,
+    // base amount, usd notional or % of free collateral (per leg)
+    pub target_position_size: PositionSize,
+    // simulate txs instead of sending them
+    pub simulate: bool,
+    // close all open positions
+    pub close: bool,
+    pub profitability: ProfitabilityConfig,
+    pub auction: AuctionConfig,
+    // time entries on the funding expected over this horizon instead of the predicted rate
+    pub funding_horizon_seconds: Option<i64>,
+    // max |perp base + spot base| left after trading (base precision)
+    pub delta_tolerance: u64,
+    // corrective orders to send before unwinding the imbalance instead
+    pub max_reconcile_attempts: u32,
+    // entry/exit thresholds, min hold + flip confirmations (unless a market overrides it)
+    pub policy: PolicyConfig,
+    // both legs are shrunk once maintenance health is below min_health, back up to delever_health
+    // (PERCENTAGE_PRECISION)
+    pub min_health: u128,
+    pub delever_health: u128,
+    // entries are clamped to a max leverage / initial margin utilization
+    pub sizing: SizingConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArbAction {
+    // move to the target position
+    Open,
+    // keep whatever is open but dont enter anything new
+    Hold,
+    // flatten both legs
+    Close,
+}
+
+// result of evaluating funding vs borrow (rates are APR % in 1e9 precision)
+#[derive(Debug, Clone, Copy)]
+pub struct Opportunity {
+    pub perp_market_index: u16,
+    pub spot_market_index: u16,
+    // base precision (1e9)
+    pub target_position_size: u64,
+    // validated perp oracle price (PRICE_PRECISION)
+    pub oracle_price: i64,
+    // received by the funding direction's side (negative = expected to pay over the horizon)
+    pub funding_rate: i128,
+    pub funding: FundingProjection,
+    pub funding_direction: PositionDirection,
+    pub borrow_rate: u128,
+    pub deposit_rate: u128,
+    // funding +/- the spot leg's rate (borrow paid when long, deposit earned when short)
+    pub delta: u128,
+    pub target_perp_position: PositionDirection,
+    pub target_spot_position: SpotBalanceType,
+    pub profitability: Profitability,
+    pub action: ArbAction,
+}
+
+#[derive(Default)]
+pub struct RebalanceReport {
+    pub planned: Vec<OrderParams>,
+    pub sent: Vec<Signature>,
+    pub simulations: Vec<SimulationReport>,
+}
+
+pub struct ArbEngine {
+    connection: RpcClient,
+    signer: Rc<dyn Signer>,
+    config: ArbConfig,
+    state: Pubkey,
+    user_address: Pubkey,
+    // refreshed on every evaluate()
+    state_account: Option<State>,
+    cached_accounts: HashMap<Pubkey, Market>,
+    remaining_accounts: Vec<AccountMeta>,
+    recorder: Option<Recorder>,
+    // per perp market
+    market_policies: HashMap<u16, PolicyConfig>,
+    policy_states: HashMap<u16, PolicyState>,
+    // base size (1e9) a notional / % of free collateral size resolved to when entering
+    entry_sizes: HashMap<u16, u64>,
+}
+
+impl ArbEngine {
+    pub fn new(connection: RpcClient, signer: Rc<dyn Signer>, config: ArbConfig) -> Self {
+        let state = get_state_public_key(&config.program_id);
+        let user_address = get_user_public_key(&signer.pubkey(), config.subaccount_id, &config.program_id);
+
+        ArbEngine {
+            connection,
+            signer,
+            config,
+            state,
+            user_address,
+            state_account: None,
+            cached_accounts: HashMap::new(),
+            remaining_accounts: vec![],
+            recorder: None,
+            market_policies: HashMap::new(),
+            policy_states: HashMap::new(),
+            entry_sizes: HashMap::new(),
+        }
+    }
+
+    // records every evaluated pair
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    // overrides config.policy for these perp markets
+    pub fn with_market_policies(mut self, market_policies: &[MarketPolicy]) -> Self {
+        for market_policy in market_policies.iter() {
+            self.market_policies.insert(market_policy.perp_market_index, market_policy.config);
+        }
+        self
+    }
+
+    pub fn config(&self) -> &ArbConfig {
+        &self.config
+    }
+
+    pub fn connection(&self) -> &RpcClient {
+        &self.connection
+    }
+
+    pub fn user_address(&self) -> Pubkey {
+        self.user_address
+    }
+
+    // cache perp/spot data once for addresses
+    // cache markets once to re-use in get_remaining_accounts
+    pub fn refresh(&mut self) -> Result<()> {
+        let state_account = get_state(&self.connection, &self.state)?;
+        self.cached_accounts = get_cached_accounts(&self.connection, &state_account, &self.config.program_id)?;
+        self.remaining_accounts = get_remaining_accounts(&state_account, &self.cached_accounts, &self.config.program_id)?;
+        self.state_account = Some(state_account);
+        Ok(())
+    }
+
+    pub fn state_account(&self) -> Result<&State> {
+        self.state_account.as_ref().ok_or(DriftError::StateNotLoaded)
+    }
+
+    // on-chain copy as of the last refresh()
+    pub fn get_perp_market(&self, market_index: u16) -> Result<PerpMarket> {
+        let perp_address = get_perp_market_public_key(market_index, &self.config.program_id);
+        get_cached_perp_market(&self.cached_accounts, &perp_address)
+    }
+
+    pub fn get_spot_market(&self, market_index: u16) -> Result<SpotMarket> {
+        let spot_address = get_spot_market_public_key(market_index, &self.config.program_id);
+        get_cached_spot_market(&self.cached_accounts, &spot_address)
+    }
+
+    pub fn perp_market(&self) -> Result<PerpMarket> {
+        self.get_perp_market(self.config.perp_market_index)
+    }
+
+    pub fn spot_market(&self) -> Result<SpotMarket> {
+        self.get_spot_market(self.config.spot_market_index)
+    }
+
+    // margin of the user's account using drift's margin math over the cached markets
+    pub fn margin(&self, user: &User, margin_requirement_type: MarginRequirementType) -> Result<MarginInfo> {
+        let keys: Vec<Pubkey> = self.remaining_accounts.iter().map(|meta| meta.pubkey).collect();
+        let mut snapshots = get_account_snapshots(&self.connection, &keys)?;
+        let slot = self.connection.get_slot()?;
+        calculate_margin(user, &mut snapshots, slot, margin_requirement_type)
+    }
+
+    // maintenance health of the account + the approximate liquidation price of a pair
+    pub fn health(&self, user: &User, margin: &MarginInfo, perp_market_index: u16, spot_market_index: u16) -> Result<Health> {
+        let perp_market = self.get_perp_market(perp_market_index)?;
+        let spot_market = self.get_spot_market(spot_market_index)?;
+        let (current_perp, current_spot) = get_current_legs(user, &perp_market, &spot_market)?;
+
+        Ok(compute_health(
+            margin,
+            current_perp,
+            current_spot,
+            &perp_market,
+            &spot_market,
+            perp_market.amm.historical_oracle_data.last_oracle_price,
+        )?)
+    }
+
+    // base amount (1e9) of a position size at the perp's oracle price, rounded up to its step size
+    // -- notional / % of free collateral sizes are only resolved entering from flat: re-resolving them
+    // on an open arb would resize it on every price move and shrink it as its own margin uses up the
+    // free collateral, so the open arb is rebalanced toward the size resolved at entry instead
+    pub fn resolve_position_size(
+        &mut self,
+        user: &User,
+        perp_market_index: u16,
+        spot_market_index: u16,
+        position_size: PositionSize,
+        oracle_price: i64,
+    ) -> Result<u64> {
+        let perp_market = self.get_perp_market(perp_market_index)?;
+        if let PositionSize::Base(base_asset_amount) = position_size {
+            return Ok(base_asset_amount);
+        }
+
+        let spot_market = self.get_spot_market(spot_market_index)?;
+        let (current_perp, current_spot) = get_current_legs(user, &perp_market, &spot_market)?;
+        let held = current_perp.unsigned_abs().max(current_spot.unsigned_abs()) as u64;
+        if held > 0 {
+            // found open (eg. after a restart) -> the held size is the entry size
+            let entry_size = *self.entry_sizes.entry(perp_market_index).or_insert(held);
+            println!(
+                "SIZING: {:?} -> {} base resolved at entry (holding {})",
+                position_size,
+                entry_size as f64 / BASE_PRECISION_U64 as f64,
+                held as f64 / BASE_PRECISION_U64 as f64,
+            );
+            return Ok(entry_size);
+        }
+
+        let free_collateral = match position_size {
+            PositionSize::FreeCollateral(_) => {
+                let margin = self.margin(user, MarginRequirementType::Initial)?;
+                // would resolve to nothing to enter
+                if margin.free_collateral == 0 {
+                    return Err(DriftError::InsufficientCollateral {
+                        free_collateral: margin.free_collateral,
+                        margin_requirement: margin.margin_requirement,
+                    });
+                }
+                margin.free_collateral
+            }
+            _ => 0,
+        };
+
+        let base_asset_amount = get_base_asset_amount(position_size, oracle_price, free_collateral, perp_market.amm.order_step_size)?;
+        println!("SIZING: {:?} -> {} base", position_size, base_asset_amount as f64 / BASE_PRECISION_U64 as f64);
+        self.entry_sizes.insert(perp_market_index, base_asset_amount);
+        Ok(base_asset_amount)
+    }
+
+    pub fn evaluate(&mut self) -> Result<Opportunity> {
+        self.refresh()?;
+
+        let ArbConfig {
+            perp_market_index,
+            spot_market_index,
+            target_position_size,
+            ..
+        } = self.config;
+        self.evaluate_pair(perp_market_index, spot_market_index, target_position_size)
+    }
+
+    // evaluates a perp/spot pair against the markets cached by the last refresh()
+    pub fn evaluate_pair(
+        &mut self,
+        perp_market_index: u16,
+        spot_market_index: u16,
+        target_position_size: PositionSize,
+    ) -> Result<Opportunity> {
+        let mut perp_market = self.get_perp_market(perp_market_index)?;
+        let spot_market = self.get_spot_market(spot_market_index)?;
+
+        // both legs trade off these -- nothing about the pair is evaluated on an oracle drift wouldnt fill at
+        let oracle_price = self.get_valid_oracle_price(MarketType::Perp, &perp_market, &spot_market)?;
+        self.get_valid_oracle_price(MarketType::Spot, &perp_market, &spot_market)?;
+
+        let spot_symbol = get_market_symbol(&spot_market.name);
+        let perp_symbol = get_market_symbol(&perp_market.name);
+        println!("spot/perp name: {} {}", spot_symbol, perp_symbol);
+        if spot_symbol != perp_symbol {
+            return Err(DriftError::MarketMismatch(perp_symbol, spot_symbol))
+        }
+
+        // 1e9 precision
+        let funding_horizon_seconds = self
+            .config
+            .funding_horizon_seconds
+            .unwrap_or(self.config.profitability.holding_horizon_seconds);
+        let funding = compute_funding_projection(&self.connection, &mut perp_market, funding_horizon_seconds)?;
+        print_funding_projection(&funding);
+
+        // predicted rate unless we're timing entries over a funding horizon -- the blend keeps its
+        // sign so a horizon expected to pay funding lowers the carry
+        let funding_direction = funding.direction;
+        let funding_rate = match self.config.funding_horizon_seconds {
+            Some(_) => funding.blended_apr,
+            None => funding.predicted_apr as i128,
+        };
+        println!("funding APR: {:#?} {:#?}", funding_rate, funding_direction);
+
+        // 1e9 precision
+        let borrow_rate = compute_borrow_rate(&spot_market)?.mul(10_u128.pow(5_u32));
+        println!("borrow APR: {:#?}", borrow_rate);
+
+        // 1e9 precision
+        let deposit_rate = compute_deposit_rate(&spot_market)?.mul(10_u128.pow(5_u32));
+        println!("deposit APR: {:#?}", deposit_rate);
+
+        let gross_apr = gross_apr(funding_rate, funding_direction, borrow_rate, deposit_rate)?;
+        let delta = gross_apr.max(0) as u128;
+        println!("INFO: funding delta % {}", delta as f64 / 1e9);
+
+        let user = get_user(&self.connection, &self.user_address)?;
+        let target_position_size =
+            self.resolve_position_size(&user, perp_market_index, spot_market_index, target_position_size, oracle_price)?;
+
+        let policy = get_market_policy(&self.config.policy, &self.market_policies, perp_market_index);
+
+        // fees + slippage amortized over the holding horizon vs the policy's entry threshold
+        let costs = estimate_trade_costs(
+            self.state_account()?,
+            &perp_market,
+            target_position_size,
+            funding_direction,
+            &self.config.profitability,
+        )?;
+        let profitability = compute_profitability(delta, costs, &self.config.profitability, policy.entry_net_apr)?;
+        print_profitability(&profitability);
+
+        let (current_perp, current_spot) = get_current_legs(&user, &perp_market, &spot_market)?;
+        let policy_state = self.policy_states.entry(perp_market_index).or_default();
+        policy_state.sync(get_held_direction(current_perp, current_spot));
+        let held_direction = policy_state.held_direction();
+
+        let action = policy_state.decide(&policy, &PolicyInput {
+            ts: funding.ts,
+            direction: funding_direction,
+            gross_apr,
+            should_enter: profitability.should_enter,
+            close: self.config.close,
+        });
+        match action {
+            ArbAction::Close if self.config.close => println!("closing positions..."),
+            ArbAction::Close => println!("carry below the exit APR or funding flipped... closing positions"),
+            ArbAction::Hold if held_direction.is_some_and(|direction| direction != funding_direction) => {
+                println!("funding flipped to {:?}... holding until confirmed", funding_direction)
+            }
+            ArbAction::Hold => println!("not profitable enough to enter or exit... holding current positions"),
+            ArbAction::Open => {}
+        }
+
+        let (target_perp_position, target_spot_position) = match funding_direction {
+            PositionDirection::Long => (PositionDirection::Long, SpotBalanceType::Borrow),
+            PositionDirection::Short => (PositionDirection::Short, SpotBalanceType::Deposit),
+        };
+        println!("target perp/spot positions: {:#?} {:#?}", target_perp_position, target_spot_position);
+
+        if let Some(recorder) = &self.recorder {
+            recorder.record(&RateSample {
+                ts: funding.ts,
+                slot: funding.slot,
+                perp_market_index,
+                spot_market_index,
+                symbol: perp_symbol,
+                funding_apr: funding.predicted_apr,
+                funding_direction,
+                last_funding_apr: funding.last_apr,
+                last_24h_avg_funding_apr: funding.last_24h_avg_apr,
+                borrow_apr: borrow_rate,
+                deposit_apr: deposit_rate,
+                utilization: compute_utilization(&spot_market)?,
+                oracle_price,
+                oracle_twap: perp_market.amm.historical_oracle_data.last_oracle_price_twap,
+                mark_twap: perp_market.amm.last_mark_price_twap,
+                net_apr: profitability.net_apr,
+                action: format!("{:?}", action),
+            })?;
+        }
+
+        Ok(Opportunity {
+            perp_market_index,
+            spot_market_index,
+            target_position_size,
+            oracle_price,
+            funding_rate,
+            funding,
+            funding_direction,
+            borrow_rate,
+            deposit_rate,
+            delta,
+            target_perp_position,
+            target_spot_position,
+            profitability,
+            action,
+        })
+    }
+
+    pub fn rebalance(&mut self, opportunity: &Opportunity) -> Result<RebalanceReport> {
+        let subaccount_id = self.config.subaccount_id;
+        let Opportunity {
+            perp_market_index,
+            spot_market_index,
+            target_position_size,
+            target_perp_position,
+            target_spot_position,
+            oracle_price,
+            action,
+            ..
+        } = *opportunity;
+
+        let mut report = RebalanceReport::default();
+        if action == ArbAction::Hold {
+            println!("holding, nothing to rebalance...");
+            return Ok(report);
+        }
+
+        let perp_market = self.get_perp_market(perp_market_index)?;
+        let spot_market = self.get_spot_market(spot_market_index)?;
+        let user = get_user(&self.connection, &self.user_address)?;
+
+        // size the entry to what the collateral supports after the trade
+        let mut target_position_size = target_position_size;
+        let margin = match action {
+            ArbAction::Open => {
+                let margin = self.margin(&user, MarginRequirementType::Initial)?;
+                let (current_perp, _) = get_current_legs(&user, &perp_market, &spot_market)?;
+                let held = match target_perp_position {
+                    PositionDirection::Long => current_perp.max(0),
+                    PositionDirection::Short => (-current_perp).max(0),
+                } as u64;
+                let sizing = size_position(
+                    &margin,
+                    held,
+                    target_position_size,
+                    target_perp_position,
+                    &perp_market,
+                    &spot_market,
+                    oracle_price,
+                    &self.config.sizing,
+                )?;
+                print_sizing(&sizing);
+                target_position_size = sizing.size;
+                Some(margin)
+            }
+            _ => None,
+        };
+
+        let target = RebalanceTarget {
+            base_asset_amount: target_position_size,
+            perp_direction: target_perp_position,
+            spot_balance_type: target_spot_position,
+            close: action == ArbAction::Close,
+        };
+        let intents = plan_rebalance(&user, &perp_market, &spot_market, &target)?;
+        if intents.is_empty() {
+            println!("in correct position, doing nothing...");
+        }
+
+        // entering with no free collateral can only be rejected
+        let increases_position = intents.iter().any(|intent| !intent.reduce_only);
+        if let Some(margin) = margin {
+            if increases_position && margin.free_collateral == 0 {
+                return Err(DriftError::InsufficientCollateral {
+                    free_collateral: margin.free_collateral,
+                    margin_requirement: margin.margin_requirement,
+                });
+            }
+        }
+
+        // both legs (+ margin enable) go in one tx so either both orders are placed or neither is
+        let mut ixs = vec![];
+        let has_spot_order = intents.iter().any(|intent| intent.market_type == MarketType::Spot);
+        if has_spot_order && !user.is_margin_trading_enabled {
+            println!("SPOT: enabling margin trading...");
+            ixs.push(build_instruction(
+                &self.config.program_id,
+                accounts::UpdateUser {
+                    user: self.user_address,
+                    authority: self.signer.pubkey(),
+                },
+                ix::UpdateUserMarginTradingEnabled {
+                    _sub_account_id: subaccount_id,
+                    margin_trading_enabled: true
+                },
+                &[],
+            ));
+        }
+        ixs.extend(self.place_orders_ixs(&intents, &perp_market, &spot_market, &mut report)?);
+
+        if !ixs.is_empty() {
+            self.execute("ARB", &ixs, &mut report)?;
+        }
+
+        // placing is atomic but filling isnt -- make sure the legs still offset each other
+        if !self.config.simulate && !report.sent.is_empty() {
+            let reconciled = self.reconcile(perp_market_index, spot_market_index, &target)?;
+            report.planned.extend(reconciled.planned);
+            report.sent.extend(reconciled.sent);
+        }
+
+        Ok(report)
+    }
+
+    // creates the UserStats + User accounts if they're missing, then deposits usdc (QUOTE_PRECISION)
+    // from the owner's associated token account -- all in one tx
+    pub fn initialize(&mut self, deposit_amount: Option<u64>) -> Result<RebalanceReport> {
+        self.refresh()?;
+
+        let mut report = RebalanceReport::default();
+        let authority = self.signer.pubkey();
+        let user_stats = get_user_stats_public_key(&authority, &self.config.program_id);
+        let accounts = self.connection.get_multiple_accounts(&[user_stats, self.user_address])?;
+
+        let mut ixs = vec![];
+        if accounts[0].is_none() {
+            println!("INIT: creating user stats {}...", user_stats);
+            ixs.push(build_instruction(
+                &self.config.program_id,
+                accounts::InitializeUserStats {
+                    user_stats,
+                    state: self.state,
+                    authority,
+                    payer: authority,
+                    rent: sysvar::rent::ID,
+                    system_program: system_program::ID,
+                },
+                ix::InitializeUserStats {},
+                &[],
+            ));
+        }
+        if accounts[1].is_none() {
+            println!("INIT: creating user {} (subaccount {})...", self.user_address, self.config.subaccount_id);
+            let mut name = [b' '; 32];
+            name[..USER_NAME.len()].copy_from_slice(USER_NAME);
+            ixs.push(build_instruction(
+                &self.config.program_id,
+                accounts::InitializeUser {
+                    user: self.user_address,
+                    user_stats,
+                    state: self.state,
+                    authority,
+                    payer: authority,
+                    rent: sysvar::rent::ID,
+                    system_program: system_program::ID,
+                },
+                ix::InitializeUser {
+                    sub_account_id: self.config.subaccount_id,
+                    name,
+                },
+                &[],
+            ));
+        }
+        if ixs.is_empty() {
+            println!("INIT: user already exists...");
+        }
+
+        if let Some(amount) = deposit_amount.filter(|amount| *amount > 0) {
+            // spot market 0 = usdc
+            let spot_market = self.get_spot_market(0)?;
+            let user_token_account = derive_token_address(&authority, &spot_market.mint);
+            println!(
+                "INIT: depositing {} USDC from {}...",
+                amount as f64 / QUOTE_PRECISION as f64,
+                user_token_account,
+            );
+            ixs.push(build_instruction(
+                &self.config.program_id,
+                accounts::Deposit {
+                    state: self.state,
+                    user: self.user_address,
+                    user_stats,
+                    authority,
+                    spot_market_vault: get_spot_market_vault_public_key(0, &self.config.program_id),
+                    user_token_account,
+                    token_program: *TOKEN_PROGRAM_ID,
+                },
+                ix::Deposit {
+                    market_index: 0,
+                    amount,
+                    reduce_only: false,
+                },
+                &self.remaining_accounts,
+            ));
+        }
+
+        if !ixs.is_empty() {
+            self.execute("INIT", &ixs, &mut report)?;
+        }
+        Ok(report)
+    }
+
+    // withdraws usdc (QUOTE_PRECISION) back to the owner's associated token account, None sweeps
+    // all free collateral above the reserve -- refused if maintenance health would end up below
+    // delever_health (so a withdrawal never triggers a delever)
+    pub fn withdraw(&mut self, amount: Option<u64>, reserve: u64) -> Result<RebalanceReport> {
+        self.refresh()?;
+
+        let mut report = RebalanceReport::default();
+        let user = get_user(&self.connection, &self.user_address)?;
+        let initial_margin = self.margin(&user, MarginRequirementType::Initial)?;
+        // spot market 0 = usdc
+        let spot_market = self.get_spot_market(0)?;
+        // free collateral also counts other collateral, reduce only never withdraws past the deposit
+        let deposit = match user.get_spot_position(0) {
+            Some(position) => position.get_signed_token_amount(&spot_market)?.max(0).unsigned_abs(),
+            None => 0,
+        };
+        let amount = match amount {
+            // an explicit amount is never silently shrunk
+            Some(amount) if amount as u128 > deposit => {
+                return Err(DriftError::InsufficientDeposit { amount, deposit });
+            }
+            Some(amount) => amount,
+            None => initial_margin.free_collateral.saturating_sub(reserve as u128).min(deposit) as u64,
+        };
+        if amount == 0 {
+            println!(
+                "WITHDRAW: nothing to withdraw (free collateral {} reserve {} usdc deposit {})...",
+                initial_margin.free_collateral as f64 / QUOTE_PRECISION as f64,
+                reserve as f64 / QUOTE_PRECISION as f64,
+                deposit as f64 / QUOTE_PRECISION as f64,
+            );
+            return Ok(report);
+        }
+
+        // usdc is weighted 1:1 so the collateral drops by the amount while the requirement stays put
+        let maintenance_margin = self.margin(&user, MarginRequirementType::Maintenance)?;
+        let health = calculate_health(
+            maintenance_margin.total_collateral - amount as i128,
+            maintenance_margin.margin_requirement,
+        )?;
+        println!(
+            "WITHDRAW: {} USDC leaves health at {}%",
+            amount as f64 / QUOTE_PRECISION as f64,
+            health as f64 / 1e4,
+        );
+        if health < self.config.delever_health {
+            return Err(DriftError::UnsafeWithdrawal {
+                amount,
+                health,
+                min_health: self.config.delever_health,
+            });
+        }
+
+        let authority = self.signer.pubkey();
+        let ix = build_instruction(
+            &self.config.program_id,
+            accounts::Withdraw {
+                state: self.state,
+                user: self.user_address,
+                user_stats: get_user_stats_public_key(&authority, &self.config.program_id),
+                authority,
+                spot_market_vault: get_spot_market_vault_public_key(0, &self.config.program_id),
+                drift_signer: get_drift_signer_public_key(&self.config.program_id),
+                user_token_account: derive_token_address(&authority, &spot_market.mint),
+                token_program: *TOKEN_PROGRAM_ID,
+            },
+            // reduce only so it never borrows usdc
+            ix::Withdraw {
+                market_index: 0,
+                amount,
+                reduce_only: true,
+            },
+            &self.remaining_accounts,
+        );
+        self.execute("WITHDRAW", &[ix], &mut report)?;
+        Ok(report)
+    }
+
+    // once health drops below min_health every pair's legs are shrunk by the same fraction
+    // so health is back at delever_health
+    pub fn delever(&mut self, markets: &[MarketPair]) -> Result<RebalanceReport> {
+        let mut report = RebalanceReport::default();
+        let user = get_user(&self.connection, &self.user_address)?;
+        // collateral + requirement are account wide, the liquidation price is per pair
+        let margin = self.margin(&user, MarginRequirementType::Maintenance)?;
+
+        let mut healths = vec![];
+        for market in markets.iter() {
+            let health = self.health(&user, &margin, market.perp_market_index, market.spot_market_index)?;
+            println!("HEALTH: perp {} / spot {}", market.perp_market_index, market.spot_market_index);
+            print_health(&health);
+            healths.push(health);
+        }
+        let health = match healths.first() {
+            Some(health) if health.health < self.config.min_health => *health,
+            _ => return Ok(report),
+        };
+
+        let fraction = compute_delever_fraction(&health, self.config.delever_health)?;
+        println!(
+            "DELEVER: health below {}%... reducing both legs by {}%",
+            self.config.min_health as f64 / 1e4,
+            fraction as f64 / 1e4,
+        );
+        for market in markets.iter() {
+            let perp_market = self.get_perp_market(market.perp_market_index)?;
+            let spot_market = self.get_spot_market(market.spot_market_index)?;
+            let intents = plan_delever(&user, &perp_market, &spot_market, fraction)?;
+            if intents.is_empty() {
+                continue;
+            }
+            // both legs at once so the reduction stays delta neutral
+            let ixs = self.place_orders_ixs(&intents, &perp_market, &spot_market, &mut report)?;
+            self.execute("DELEVER", &ixs, &mut report)?;
+        }
+
+        Ok(report)
+    }
+
+    // holds arbs on several markets at once from the one subaccount: capital is split
+    // across the markets worth entering and the whole book is rebalanced to it -- a market
+    // that fails (eg. an invalid oracle) is logged and skipped so the rest of the book still trades
+    pub fn rebalance_portfolio(&mut self, markets: &[MarketPair], policy: AllocationPolicy) -> Result<Vec<RebalanceReport>> {
+        self.refresh()?;
+
+        let user = get_user(&self.connection, &self.user_address)?;
+        let margin = self.margin(&user, MarginRequirementType::Initial)?;
+        println!(
+            "PORTFOLIO: total collateral {} free collateral {}",
+            margin.total_collateral as f64 / QUOTE_PRECISION as f64,
+            margin.free_collateral as f64 / QUOTE_PRECISION as f64,
+        );
+
+        // signed perp base + QUOTE_PRECISION notional currently held per market
+        let mut held_bases = vec![];
+        let mut held_notionals = vec![];
+        for market in markets.iter() {
+            let perp_market = self.get_perp_market(market.perp_market_index)?;
+            let price = perp_market.amm.historical_oracle_data.last_oracle_price.unsigned_abs() as u128;
+            let base_asset_amount = user
+                .get_perp_position(market.perp_market_index)
+                .map_or(0, |position| position.base_asset_amount);
+
+            held_bases.push(base_asset_amount);
+            held_notionals.push(base_asset_amount.unsigned_abs() as u128 * price / BASE_PRECISION);
+        }
+
+        // the book is at most 1x total collateral per leg and can only grow by the free collateral
+        let held_notional: u128 = held_notionals.iter().sum();
+        let capital = (margin.total_collateral.max(0).unsigned_abs())
+            .min(held_notional.saturating_add(margin.free_collateral));
+
+        // size estimate (for fees + slippage) is an equal split of the capital
+        let estimated_allocation = capital / markets.len().max(1) as u128;
+        let mut opportunities = vec![];
+        // markets we hold onto (or couldnt evaluate) keep their capital
+        let mut hold_notional: u128 = 0;
+        for ((market, held_base), held_notional) in markets.iter().zip(held_bases).zip(held_notionals) {
+            println!("PORTFOLIO: evaluating perp {} / spot {}...", market.perp_market_index, market.spot_market_index);
+            let evaluated = self.evaluate_pair(
+                market.perp_market_index,
+                market.spot_market_index,
+                PositionSize::Notional(estimated_allocation),
+            );
+            match evaluated {
+                Ok(opportunity) => {
+                    if opportunity.action == ArbAction::Hold {
+                        hold_notional += held_notional;
+                    }
+                    opportunities.push((opportunity, held_base));
+                }
+                Err(e) => {
+                    println!(
+                        "ERROR: skipping perp {} / spot {}: {}",
+                        market.perp_market_index, market.spot_market_index, e
+                    );
+                    hold_notional += held_notional;
+                }
+            }
+        }
+
+        // the rest is split over the markets we enter
+        let net_carries: Vec<i128> = opportunities
+            .iter()
+            .map(|(opportunity, _)| match opportunity.action {
+                ArbAction::Open => opportunity.profitability.net_apr,
+                _ => 0,
+            })
+            .collect();
+        let allocations = allocate(&net_carries, capital.saturating_sub(hold_notional), policy)?;
+        for ((opportunity, _), allocation) in opportunities.iter_mut().zip(allocations) {
+            if opportunity.action == ArbAction::Open {
+                let price = opportunity.oracle_price.unsigned_abs() as u128;
+                opportunity.target_position_size = notional_to_base(allocation, price)?;
+                if opportunity.target_position_size == 0 {
+                    opportunity.action = ArbAction::Hold;
+                }
+            }
+            println!(
+                "PORTFOLIO: perp {} / spot {}: {:?} with {} allocated ({} base)",
+                opportunity.perp_market_index,
+                opportunity.spot_market_index,
+                opportunity.action,
+                allocation as f64 / QUOTE_PRECISION as f64,
+                opportunity.target_position_size as f64 / BASE_PRECISION_U64 as f64,
+            );
+        }
+
+        // closes + shrinking positions free the collateral the entries need so they go first
+        let reduces = |opportunity: &Opportunity, held_base: i64| match opportunity.action {
+            ArbAction::Close => true,
+            ArbAction::Open => {
+                let held_direction = if held_base > 0 { PositionDirection::Long } else { PositionDirection::Short };
+                held_base != 0
+                    && held_direction == opportunity.target_perp_position
+                    && opportunity.target_position_size < held_base.unsigned_abs()
+            }
+            ArbAction::Hold => false,
+        };
+        opportunities.sort_by_key(|(opportunity, held_base)| !reduces(opportunity, *held_base));
+
+        let mut reports = vec![];
+        for (opportunity, _) in opportunities.iter() {
+            match self.rebalance(opportunity) {
+                Ok(report) => reports.push(report),
+                Err(e) => println!(
+                    "ERROR: rebalancing perp {} / spot {} failed: {}",
+                    opportunity.perp_market_index, opportunity.spot_market_index, e
+                ),
+            }
+        }
+
+        Ok(reports)
+    }
+
+    // waits out the auction then cancels whatever is left of our orders on the pair
+    fn settle_auction(&self, perp_market_index: u16, spot_market_index: u16, report: &mut RebalanceReport) -> Result<()> {
+        let auction_ms = (self.config.auction.auction_duration as u64 + AUCTION_BUFFER_SLOTS) * SLOT_MS;
+        println!("RECONCILE: waiting {}ms for the auction to finish...", auction_ms);
+        thread::sleep(Duration::from_millis(auction_ms));
+
+        let user = get_user(&self.connection, &self.user_address)?;
+        let has_open_orders = user.orders.iter().any(|order| {
+            order.status == OrderStatus::Open
+                && ((order.market_type == MarketType::Perp && order.market_index == perp_market_index)
+                    || (order.market_type == MarketType::Spot && order.market_index == spot_market_index))
+        });
+        if has_open_orders {
+            println!("RECONCILE: cancelling unfilled orders...");
+            let ixs = [
+                self.cancel_orders_ix(MarketType::Perp, perp_market_index),
+                self.cancel_orders_ix(MarketType::Spot, spot_market_index),
+            ];
+            self.execute("CANCEL", &ixs, report)?;
+        }
+        Ok(())
+    }
+
+    // post-trade pass: re-reads the user and sends corrective orders on the lagging leg until
+    // the net delta is within the tolerance -- if it still isnt after max_reconcile_attempts
+    // whichever leg filled more is unwound back down to the other one
+    pub fn reconcile(&mut self, perp_market_index: u16, spot_market_index: u16, target: &RebalanceTarget) -> Result<RebalanceReport> {
+        let mut report = RebalanceReport::default();
+        let perp_market = self.get_perp_market(perp_market_index)?;
+        let spot_market = self.get_spot_market(spot_market_index)?;
+
+        for attempt in 0..self.config.max_reconcile_attempts {
+            self.settle_auction(perp_market_index, spot_market_index, &mut report)?;
+
+            let user = get_user(&self.connection, &self.user_address)?;
+            let net_delta = compute_net_delta(&user, &perp_market, &spot_market)?;
+            println!("RECONCILE: net delta {} (tolerance {})", net_delta, self.config.delta_tolerance);
+
+            let intent = match plan_reconcile(&user, &perp_market, &spot_market, target, self.config.delta_tolerance)? {
+                Some(intent) => intent,
+                None => {
+                    println!("RECONCILE: legs are balanced...");
+                    return Ok(report);
+                }
+            };
+
+            println!("RECONCILE: correcting lagging leg (attempt {})...", attempt + 1);
+            let ixs = self.place_orders_ixs(&[intent], &perp_market, &spot_market, &mut report)?;
+            self.execute("RECONCILE", &ixs, &mut report)?;
+        }
+
+        self.settle_auction(perp_market_index, spot_market_index, &mut report)?;
+        let user = get_user(&self.connection, &self.user_address)?;
+        if plan_reconcile(&user, &perp_market, &spot_market, target, self.config.delta_tolerance)?.is_none() {
+            println!("RECONCILE: legs are balanced...");
+            return Ok(report);
+        }
+
+        let intents = plan_unwind(&user, &perp_market, &spot_market)?;
+        if !intents.is_empty() {
+            println!("UNWIND: legs are still imbalanced, unwinding...");
+            let ixs = self.place_orders_ixs(&intents, &perp_market, &spot_market, &mut report)?;
+            self.execute("UNWIND", &ixs, &mut report)?;
+        }
+
+        Ok(report)
+    }
+
+    // live oracle price of the leg's market -- refuses to trade when drift wouldnt
+    // consider the oracle valid for the fill
+    fn get_valid_oracle_price(&self, market_type: MarketType, perp_market: &PerpMarket, spot_market: &SpotMarket) -> Result<i64> {
+        let guard_rails = &self.state_account()?.oracle_guard_rails.validity;
+        let (oracle_pk, oracle_price_data, last_oracle_price_twap, action) = match market_type {
+            MarketType::Perp => (
+                perp_market.amm.oracle,
+                get_perp_oracle_info(&self.connection, perp_market)?,
+                perp_market.amm.historical_oracle_data.last_oracle_price_twap,
+                DriftAction::FillOrderAmm,
+            ),
+            MarketType::Spot => (
+                spot_market.oracle,
+                get_spot_oracle_info(&self.connection, spot_market)?,
+                spot_market.historical_oracle_data.last_oracle_price_twap,
+                DriftAction::FillOrderMatch,
+            ),
+        };
+
+        check_oracle_validity(&oracle_pk, &oracle_price_data, last_oracle_price_twap, guard_rails, action)?;
+        Ok(oracle_price_data.price)
+    }
+
+    // auction priced place order ixs for each intent
+    fn place_orders_ixs(
+        &self,
+        intents: &[OrderIntent],
+        perp_market: &PerpMarket,
+        spot_market: &SpotMarket,
+        report: &mut RebalanceReport,
+    ) -> Result<Vec<Instruction>> {
+        let mut ixs = vec![];
+        for intent in intents.iter() {
+            let oracle_price = self.get_valid_oracle_price(intent.market_type, perp_market, spot_market)?;
+            let auction = match intent.market_type {
+                MarketType::Perp => get_perp_auction_prices(perp_market, oracle_price, intent.base_asset_amount, intent.direction, &self.config.auction),
+                MarketType::Spot => get_spot_auction_prices(oracle_price, intent.direction, &self.config.auction),
+            }?;
+            println!("AUCTION: start {} end {} ({} slots)", auction.start_price, auction.end_price, auction.duration);
+
+            let params = get_order_params(
+                OrderType::Market,
+                intent.market_type,
+                intent.direction,
+                intent.base_asset_amount,
+                intent.market_index,
+                intent.reduce_only,
+                Some(auction),
+            );
+            report.planned.push(params);
+
+            match intent.market_type {
+                MarketType::Perp => {
+                    println!("PERP: {:?} {} (reduce only: {})", intent.direction, intent.base_asset_amount, intent.reduce_only);
+                    ixs.push(self.place_order_ix(ix::PlacePerpOrder { params }));
+                }
+                MarketType::Spot => {
+                    println!("SPOT: {:?} {}", intent.direction, intent.base_asset_amount);
+                    ixs.push(self.place_order_ix(ix::PlaceSpotOrder { params }));
+                }
+            }
+        }
+        Ok(ixs)
+    }
+
+    fn cancel_orders_ix(&self, market_type: MarketType, market_index: u16) -> Instruction {
+        build_instruction(
+            &self.config.program_id,
+            accounts::CancelOrder {
+                state: self.state,
+                user: self.user_address,
+                authority: self.signer.pubkey()
+            },
+            ix::CancelOrders {
+                market_type: Some(market_type),
+                market_index: Some(market_index),
+                direction: None,
+            },
+            &self.remaining_accounts,
+        )
+    }
+
+    fn place_order_ix(&self, args: impl anchor_client::anchor_lang::InstructionData) -> Instruction {
+        build_instruction(
+            &self.config.program_id,
+            accounts::PlaceOrder {
+                state: self.state,
+                user: self.user_address,
+                authority: self.signer.pubkey()
+            },
+            args,
+            &self.remaining_accounts,
+        )
+    }
+
+    fn execute(&self, label: &str, ixs: &[Instruction], report: &mut RebalanceReport) -> Result<()> {
+        if self.config.simulate {
+            println!("{}: simulating order...", label);
+            let simulation = simulate_transaction(&self.connection, &*self.signer, ixs)?;
+            print_simulation(label, &simulation);
+            // drift's ErrorCode when a program ix failed, otherwise whatever the runtime rejected
+            // (eg. InsufficientFundsForFee, AccountNotFound, BlockhashNotFound)
+            if let Some(code) = simulation.error_code() {
+                return Err(DriftError::OrderRejected(code));
+            }
+            if let Some(err) = &simulation.err {
+                return Err(DriftError::SimulationFailed(err.clone()));
+            }
+            report.simulations.push(simulation);
+        } else {
+            println!("{}: sending order...", label);
+            let sig = send_transaction(&self.connection, &*self.signer, ixs)?;
+            println!("sig {}", sig);
+            report.sent.push(sig);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+use anchor_client::anchor_lang::error::ERROR_CODE_OFFSET;
+
+use drift::error::ErrorCode;
+
+// error_code_from_index()
+include!(concat!(env!("OUT_DIR"), "/error_codes.rs"));
+
+// custom program error (eg. 6003 / 0x1773) -> drift's ErrorCode (eg. InsufficientCollateral)
+pub fn decode_error_code(code: u32) -> Option<ErrorCode> {
+    code.checked_sub(ERROR_CODE_OFFSET)
+        .and_then(error_code_from_index)
+}
+
+// "InsufficientCollateral: Insufficient collateral (6003 / 0x1773)"
+pub fn describe_error_code(code: u32) -> String {
+    match decode_error_code(code) {
+        Some(error_code) => format!("{}: {} ({} / {:#x})", error_code.name(), error_code, code, code),
+        None => format!("unknown error code ({} / {:#x})", code, code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_custom_program_errors() {
+        assert!(matches!(decode_error_code(6000), Some(ErrorCode::InvalidSpotMarketAuthority)));
+        assert!(matches!(decode_error_code(6003), Some(ErrorCode::InsufficientCollateral)));
+        // below the offset
+        assert!(decode_error_code(0).is_none());
+        assert!(decode_error_code(5999).is_none());
+        assert!(decode_error_code(u32::MAX).is_none());
+    }
+
+    #[test]
+    fn generated_numbering_matches_the_enum() {
+        let mut n = 0;
+        while let Some(error_code) = decode_error_code(ERROR_CODE_OFFSET + n) {
+            assert_eq!(error_code as u32, n);
+            n += 1;
+        }
+        assert!(n > 3);
+    }
+
+    #[test]
+    fn describes_known_and_unknown_codes() {
+        assert_eq!(describe_error_code(6003), format!("InsufficientCollateral: {} (6003 / 0x1773)", ErrorCode::InsufficientCollateral));
+        assert_eq!(describe_error_code(42), "unknown error code (42 / 0x2a)");
+    }
+}
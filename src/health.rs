@@ -0,0 +1,242 @@
+use drift::error::DriftResult;
+use drift::math::casting::Cast;
+use drift::math::constants::{BASE_PRECISION_I128, MARGIN_PRECISION, PERCENTAGE_PRECISION, SPOT_WEIGHT_PRECISION};
+use drift::math::safe_math::SafeMath;
+use drift::state::perp_market::PerpMarket;
+use drift::state::spot_market::SpotMarket;
+
+use crate::margin::MarginInfo;
+
+// maintenance margin of the account (QUOTE_PRECISION)
+#[derive(Debug, Clone, Copy)]
+pub struct Health {
+    pub total_collateral: i128,
+    pub maintenance_margin_requirement: u128,
+    pub free_collateral: u128,
+    // (total collateral - maintenance requirement) / total collateral (PERCENTAGE_PRECISION)
+    pub health: u128,
+    // price of the pair's underlying where free collateral hits 0 (PRICE_PRECISION)
+    // -- None when no price move liquidates the position
+    pub liquidation_price: Option<i64>,
+}
+
+// 100% with nothing at risk, 0% at liquidation
+pub fn calculate_health(total_collateral: i128, maintenance_margin_requirement: u128) -> DriftResult<u128> {
+    if maintenance_margin_requirement == 0 {
+        return Ok(PERCENTAGE_PRECISION);
+    }
+    if total_collateral <= 0 {
+        return Ok(0);
+    }
+    let total_collateral = total_collateral.unsigned_abs();
+
+    total_collateral
+        .saturating_sub(maintenance_margin_requirement)
+        .safe_mul(PERCENTAGE_PRECISION)?
+        .safe_div(total_collateral)
+}
+
+// linear approximation of where the perp + spot legs (base precision, same underlying) use
+// up the free collateral: both legs move with the oracle while the perp's maintenance
+// requirement and the spot leg's maintenance weight scale with it (ignores the imf factor
+// + every other position staying put)
+pub fn calculate_liquidation_price(
+    perp_base_asset_amount: i128,
+    spot_base_asset_amount: i128,
+    perp_market: &PerpMarket,
+    spot_market: &SpotMarket,
+    oracle_price: i64,
+    free_collateral: u128,
+) -> DriftResult<Option<i64>> {
+    // change in free collateral per 1.0 move in price (base precision)
+    let perp_requirement = perp_base_asset_amount
+        .unsigned_abs()
+        .safe_mul(perp_market.margin_ratio_maintenance.cast()?)?
+        .safe_div(MARGIN_PRECISION.cast()?)?
+        .cast::<i128>()?;
+    let spot_value = if spot_base_asset_amount >= 0 {
+        spot_base_asset_amount
+            .safe_mul(spot_market.maintenance_asset_weight.cast()?)?
+            .safe_div(SPOT_WEIGHT_PRECISION.cast()?)?
+    } else {
+        spot_base_asset_amount
+            .safe_mul(spot_market.maintenance_liability_weight.cast()?)?
+            .safe_div(SPOT_WEIGHT_PRECISION.cast()?)?
+    };
+    let slope = perp_base_asset_amount
+        .safe_add(spot_value)?
+        .safe_sub(perp_requirement)?;
+    if slope == 0 {
+        return Ok(None);
+    }
+
+    // free + slope * (liquidation price - price) = 0
+    let price_move = free_collateral
+        .cast::<i128>()?
+        .safe_mul(BASE_PRECISION_I128)?
+        .safe_div(slope)?;
+    let liquidation_price = oracle_price.cast::<i128>()?.safe_sub(price_move)?;
+
+    if liquidation_price <= 0 {
+        Ok(None)
+    } else {
+        Ok(Some(liquidation_price.cast()?))
+    }
+}
+
+pub fn compute_health(
+    margin: &MarginInfo,
+    perp_base_asset_amount: i128,
+    spot_base_asset_amount: i128,
+    perp_market: &PerpMarket,
+    spot_market: &SpotMarket,
+    oracle_price: i64,
+) -> DriftResult<Health> {
+    Ok(Health {
+        total_collateral: margin.total_collateral,
+        maintenance_margin_requirement: margin.margin_requirement,
+        free_collateral: margin.free_collateral,
+        health: calculate_health(margin.total_collateral, margin.margin_requirement)?,
+        liquidation_price: calculate_liquidation_price(
+            perp_base_asset_amount,
+            spot_base_asset_amount,
+            perp_market,
+            spot_market,
+            oracle_price,
+            margin.free_collateral,
+        )?,
+    })
+}
+
+// fraction of both legs to close (PERCENTAGE_PRECISION) so health is back at the target
+// -- the requirement shrinks with the legs while the collateral roughly stays put
+pub fn compute_delever_fraction(health: &Health, target_health: u128) -> DriftResult<u128> {
+    if health.health >= target_health || health.maintenance_margin_requirement == 0 {
+        return Ok(0);
+    }
+    if health.total_collateral <= 0 {
+        return Ok(PERCENTAGE_PRECISION);
+    }
+
+    // (tc - keep * mr) / tc = target  ->  keep = tc * (1 - target) / mr
+    let keep = health
+        .total_collateral
+        .unsigned_abs()
+        .safe_mul(PERCENTAGE_PRECISION.saturating_sub(target_health))?
+        .safe_div(health.maintenance_margin_requirement)?
+        .min(PERCENTAGE_PRECISION);
+    PERCENTAGE_PRECISION.safe_sub(keep)
+}
+
+pub fn print_health(health: &Health) {
+    let usdc = |x: u128| x as f64 / 1e6;
+    println!(
+        "HEALTH: {}% -- total collateral {} maintenance requirement {} free collateral {}",
+        health.health as f64 / PERCENTAGE_PRECISION as f64 * 100.0,
+        health.total_collateral as f64 / 1e6,
+        usdc(health.maintenance_margin_requirement),
+        usdc(health.free_collateral),
+    );
+    match health.liquidation_price {
+        Some(price) => println!("HEALTH: approx liquidation price {}", price as f64 / 1e6),
+        None => println!("HEALTH: no liquidation price"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use drift::math::constants::{PRICE_PRECISION_I64, QUOTE_PRECISION, QUOTE_PRECISION_I128};
+
+    const ONE: i128 = BASE_PRECISION_I128;
+
+    fn health(total_collateral: i128, maintenance_margin_requirement: u128) -> Health {
+        Health {
+            total_collateral,
+            maintenance_margin_requirement,
+            free_collateral: (total_collateral.max(0).unsigned_abs()).saturating_sub(maintenance_margin_requirement),
+            health: calculate_health(total_collateral, maintenance_margin_requirement).unwrap(),
+            liquidation_price: None,
+        }
+    }
+
+    // 5% perp maintenance margin, 0.9 / 1.1 spot maintenance weights
+    fn markets() -> (PerpMarket, SpotMarket) {
+        let perp_market = PerpMarket {
+            margin_ratio_maintenance: 500,
+            ..PerpMarket::default()
+        };
+        let spot_market = SpotMarket {
+            maintenance_asset_weight: 9_000,
+            maintenance_liability_weight: 11_000,
+            ..SpotMarket::default()
+        };
+        (perp_market, spot_market)
+    }
+
+    fn liquidation_price(perp: i128, spot: i128, free_collateral: u128) -> Option<i64> {
+        let (perp_market, spot_market) = markets();
+        calculate_liquidation_price(perp, spot, &perp_market, &spot_market, 100 * PRICE_PRECISION_I64, free_collateral).unwrap()
+    }
+
+    #[test]
+    fn health_is_collateral_left_over_the_requirement() {
+        assert_eq!(calculate_health(100 * QUOTE_PRECISION_I128, 25 * QUOTE_PRECISION).unwrap(), 750_000);
+        // nothing at risk
+        assert_eq!(calculate_health(100 * QUOTE_PRECISION_I128, 0).unwrap(), PERCENTAGE_PRECISION);
+        assert_eq!(calculate_health(0, 0).unwrap(), PERCENTAGE_PRECISION);
+        // at or past liquidation
+        assert_eq!(calculate_health(25 * QUOTE_PRECISION_I128, 25 * QUOTE_PRECISION).unwrap(), 0);
+        assert_eq!(calculate_health(-QUOTE_PRECISION_I128, 25 * QUOTE_PRECISION).unwrap(), 0);
+    }
+
+    #[test]
+    fn delever_fraction_restores_target_health() {
+        // 20% health, keep = 100 * (1 - 40%) / 80 = 75%
+        let fraction = compute_delever_fraction(&health(100 * QUOTE_PRECISION_I128, 80 * QUOTE_PRECISION), 400_000).unwrap();
+        assert_eq!(fraction, 250_000);
+
+        // requirement shrinks with the legs -> back at the target
+        let requirement = 80 * QUOTE_PRECISION * (PERCENTAGE_PRECISION - fraction) / PERCENTAGE_PRECISION;
+        assert_eq!(calculate_health(100 * QUOTE_PRECISION_I128, requirement).unwrap(), 400_000);
+    }
+
+    #[test]
+    fn healthy_account_is_not_delevered() {
+        // 50% health
+        assert_eq!(compute_delever_fraction(&health(100 * QUOTE_PRECISION_I128, 50 * QUOTE_PRECISION), 400_000).unwrap(), 0);
+        // exactly at the target
+        assert_eq!(compute_delever_fraction(&health(100 * QUOTE_PRECISION_I128, 60 * QUOTE_PRECISION), 400_000).unwrap(), 0);
+        // no positions
+        assert_eq!(compute_delever_fraction(&health(100 * QUOTE_PRECISION_I128, 0), 400_000).unwrap(), 0);
+    }
+
+    #[test]
+    fn no_collateral_delevers_everything() {
+        assert_eq!(compute_delever_fraction(&health(0, 10 * QUOTE_PRECISION), 400_000).unwrap(), PERCENTAGE_PRECISION);
+        assert_eq!(compute_delever_fraction(&health(-QUOTE_PRECISION_I128, 10 * QUOTE_PRECISION), 400_000).unwrap(), PERCENTAGE_PRECISION);
+    }
+
+    #[test]
+    fn arb_is_liquidated_by_a_price_rise() {
+        // long funding: perp long + borrow -- slope = 1 - 1.1 - 0.05 = -0.15 per 1.0 of price
+        assert_eq!(liquidation_price(ONE, -ONE, 15 * QUOTE_PRECISION), Some(200 * PRICE_PRECISION_I64));
+        // short funding: perp short + deposit -- slope = -1 + 0.9 - 0.05 = -0.15
+        assert_eq!(liquidation_price(-ONE, ONE, 15 * QUOTE_PRECISION), Some(200 * PRICE_PRECISION_I64));
+    }
+
+    #[test]
+    fn unhedged_perp_is_liquidated_by_a_price_drop() {
+        // slope = 1 - 0.05
+        assert_eq!(liquidation_price(ONE, 0, 19 * QUOTE_PRECISION), Some(80 * PRICE_PRECISION_I64));
+    }
+
+    #[test]
+    fn no_liquidation_price() {
+        // flat
+        assert_eq!(liquidation_price(0, 0, 15 * QUOTE_PRECISION), None);
+        // would need the price to go negative
+        assert_eq!(liquidation_price(ONE, 0, 190 * QUOTE_PRECISION), None);
+    }
+}
@@ -0,0 +1,62 @@
+#[macro_use]
+extern crate lazy_static;
+
+// Results<> + macros
+pub mod constants;
+
+// drift program error codes -> ErrorCode
+pub mod error_codes;
+
+// deriving pdas + getting accounts
+pub mod address;
+
+// funding + borrow + oracle stuff
+pub mod math;
+
+// caching accounts + remaining_accounts
+#[macro_use]
+pub mod utils; 
+
+// fees + slippage vs funding carry
+pub mod profit;
+
+// diffing current vs target positions into orders
+pub mod planner;
+
+// auction start/end prices for orders
+pub mod pricing;
+
+// building + simulating + sending txs
+pub mod tx;
+
+// ranking every perp/spot pair by carry
+pub mod scanner;
+
+// csv history of evaluated rates
+pub mod recorder;
+
+// free collateral via drift's margin math
+pub mod margin;
+
+// maintenance health + liquidation price of the arb
+pub mod health;
+
+// sizing entries to the collateral
+pub mod sizing;
+
+// splitting capital across several arbs
+pub mod allocator;
+
+// entry/exit hysteresis so noisy rates dont churn fees
+pub mod policy;
+
+// evaluating + rebalancing the arb
+pub mod engine;
+pub use engine::{ArbEngine, ArbConfig, ArbAction, Opportunity, RebalanceReport};
+
+// replaying recorded rates through the engine's decisions
+pub mod backtest;
+
+// market + user builders shared by the unit tests
+#[cfg(test)]
+mod test_utils;
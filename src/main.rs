@@ -1,90 +1,62 @@
-use std::ops::Mul;
+use std::collections::HashMap;
+use std::process;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use drift::math::position::direction_to_close_position;
 use anchor_client::solana_client::rpc_client::RpcClient;
 use anchor_client::solana_sdk::commitment_config::CommitmentConfig;
+use anchor_client::solana_sdk::pubkey::Pubkey;
 use anchor_client::solana_sdk::signature::Signer;
 use anchor_client::solana_sdk::signature::read_keypair_file;
-use anchor_client::{Client, Cluster};
-
-use drift::state::user::{OrderType, MarketType};
-use drift::state::spot_market::SpotBalanceType;
+use anchor_client::Cluster;
 
 use drift::math::constants::*;
-use drift::math::orders::standardize_base_asset_amount_ceil;
-
-use drift::instructions::OrderParams;
-use drift::controller::position::PositionDirection;
-
-// anchor program ixs
-use drift::instruction as ix;
-use drift::accounts;
-
-#[macro_use]
-extern crate lazy_static;
-
-// deriving pdas + getting accounts
-mod address;
-use address::*;
-
-// funding + borrow + oracle stuff
-mod math;
-use math::{compute_funding_rate, compute_borrow_rate}; 
-
-// Results<> + macros
-mod constants;
-use constants::*;
-
-// caching accounts + remaining_accounts
-#[macro_use]
-mod utils; 
-use utils::*;
-
-pub fn get_order_params(
-    order_type: OrderType, 
-    market_type: MarketType, 
-    direction: PositionDirection, 
-    base_asset_amount: u64, 
-    market_index: u16, 
-    reduce_only: bool,
-) -> OrderParams {
-    // todo: better auction start/end price
-    // start = oracle 
-    // end = swap impact 
-
-    OrderParams { 
-        order_type, 
-        market_type, 
-        direction, 
-        base_asset_amount,
-        market_index,
-        reduce_only,
-        user_order_id: 0, 
-        price: 0,
-        post_only: false,
-        immediate_or_cancel: false,
-        trigger_price: None,
-        trigger_condition: drift::state::user::OrderTriggerCondition::Above,
-        oracle_price_offset: None,
-        auction_duration: None,
-        max_ts: None,
-        auction_start_price: None,
-        auction_end_price: None,
-    }
-}
 
-use clap::Parser;
+use drift_funding_arb::address::*;
+use drift_funding_arb::allocator::{AllocationPolicy, MarketPair};
+use drift_funding_arb::backtest::{run_backtest, print_backtest, BacktestConfig};
+use drift_funding_arb::constants::*;
+use drift_funding_arb::policy::{get_market_policy, MarketPolicy, PolicyConfig};
+use drift_funding_arb::pricing::AuctionConfig;
+use drift_funding_arb::profit::ProfitabilityConfig;
+use drift_funding_arb::recorder::{read_samples, Recorder};
+use drift_funding_arb::scanner::{scan_markets, print_scan};
+use drift_funding_arb::sizing::{PositionSize, SizingConfig};
+use drift_funding_arb::utils::get_cached_accounts;
+use drift_funding_arb::{ArbEngine, ArbConfig};
+
+use clap::{CommandFactory, ErrorKind, Parser, Subcommand};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// keypair for owner 
+    /// mainnet, devnet, localnet (or an rpc url)
+    #[clap(long, default_value = "mainnet")]
+    cluster: Cluster,
+    /// rpc url (overrides the cluster's)
+    #[clap(long)]
+    rpc_url: Option<String>,
+    /// drift program id (eg. a locally deployed program)
+    #[clap(long)]
+    program_id: Option<Pubkey>,
+    /// processed, confirmed or finalized
+    #[clap(long, default_value = "confirmed")]
+    commitment: CommitmentConfig,
+    /// keypair for owner (required unless scanning or backtesting)
     #[clap(long, short)]
-    keypair_path: String,
-    /// position size of the arb  (with precision 10)
+    keypair_path: Option<String>,
+    /// position size of the arb  (with precision 10) (required to trade unless sized by --notional-usd / --collateral-pct)
     #[clap(long, short)]
-    target_position_size: u64,
+    target_position_size: Option<u64>,
+    /// position size of the arb in USD per leg (instead of -t)
+    #[clap(long, conflicts_with_all = &["target-position-size", "collateral-pct"])]
+    notional_usd: Option<f64>,
+    /// position size of the arb as a % of free collateral per leg (instead of -t)
+    #[clap(long, conflicts_with_all = &["target-position-size", "notional-usd"])]
+    collateral_pct: Option<f64>,
     /// subaccount id of owner
     #[clap(long, default_value_t = 0)]
     subaccount_id: u16,
@@ -100,247 +72,427 @@ struct Args {
     /// will close all open positions
     #[clap(long, short, action)]
     close: bool,
+    /// min net APR % (after fees + slippage) required to enter the arb
+    #[clap(long, default_value_t = 0.0)]
+    min_net_apr: f64,
+    /// close an open arb once its carry APR % (funding -/+ borrow/deposit, before costs) falls to this
+    #[clap(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    exit_apr: f64,
+    /// hours an arb is held before it can be closed or flipped
+    #[clap(long, default_value_t = 0.0)]
+    min_hold_hours: f64,
+    /// consecutive passes funding has to favour the other side before flipping the arb
+    #[clap(long, default_value_t = 1)]
+    flip_confirmations: u32,
+    /// per perp market overrides, eg. '0:5:1:24:3' = perp 0 enters at 5% net, exits at 1%, holds >= 24h, flips after 3 passes
+    #[clap(long, value_delimiter = ',')]
+    market_policy: Vec<MarketPolicy>,
+    /// days we expect to hold the arb for (entry + exit costs are amortized over it)
+    #[clap(long, default_value_t = 7.0)]
+    holding_horizon_days: f64,
+    /// estimated spot slippage in bps
+    #[clap(long, default_value_t = 10)]
+    spot_slippage_bps: u64,
+    /// enter on the funding expected over this many hours (next payment at the predicted rate, the rest at the 24h avg) instead of the predicted rate
+    #[clap(long)]
+    funding_horizon_hours: Option<f64>,
+    /// append every evaluated sample (rates, twaps, utilization, decision) to this csv
+    #[clap(long)]
+    record: Option<String>,
+    /// slots the order auction runs for (oracle -> swap impact price)
+    #[clap(long, default_value_t = 10)]
+    auction_duration: u8,
+    /// max slippage from the oracle price in bps (bounds the auction end price)
+    #[clap(long, default_value_t = 50)]
+    max_slippage_bps: u64,
+    /// max net delta (perp base + spot base) left after trading before correcting the lagging leg
+    #[clap(long, default_value_t = 0.0)]
+    delta_tolerance: f64,
+    /// corrective orders to try before unwinding an imbalanced leg instead
+    #[clap(long, default_value_t = 3)]
+    max_reconcile_attempts: u32,
+    /// shrink both legs once maintenance health (% of collateral above the maintenance requirement) drops below this
+    #[clap(long, default_value_t = 20.0)]
+    min_health: f64,
+    /// health to restore when deleveraging
+    #[clap(long, default_value_t = 40.0)]
+    delever_health: f64,
+    /// max (perp + spot notional) / total collateral after entering, eg. 3 (the entry is sized down to fit)
+    #[clap(long)]
+    max_leverage: Option<f64>,
+    /// max % of total collateral the initial margin requirement may use after entering (the entry is sized down to fit)
+    #[clap(long, default_value_t = 90.0)]
+    max_margin_utilization: f64,
+    /// hold arbs on several perp:spot pairs at once, eg. '0:1,1:2' (sizes come from the allocation policy)
+    #[clap(long, value_delimiter = ',')]
+    markets: Vec<MarketPair>,
+    /// how to split free collateral across --markets: equal, carry or capped
+    #[clap(long, default_value = "equal")]
+    allocation: String,
+    /// max USDC allocated to a single market (required for --allocation capped)
+    #[clap(long)]
+    max_market_allocation: Option<f64>,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-fn main() -> Result<()> {
-    let Args { 
-        keypair_path, 
-        subaccount_id, 
-        perp_market_index, 
-        spot_market_index, 
-        mut target_position_size, 
-        mut simulate,
-        close,
-    } = Args::parse();
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// keep running and re-evaluate the arb on a schedule (until SIGINT/SIGTERM)
+    Run {
+        /// seconds between passes (defaults to the perp market's funding period)
+        #[clap(long, parse(try_from_str = parse_interval))]
+        interval: Option<i64>,
+    },
+    /// rank every perp/spot pair by net carry (funding -/+ borrow/deposit)
+    Scan,
+    /// create the drift user (+ user stats) for --subaccount-id if missing and optionally deposit USDC collateral
+    Init {
+        /// USDC to deposit from the owner's associated token account
+        #[clap(long)]
+        deposit: Option<f64>,
+    },
+    /// withdraw USDC (or sweep free collateral above a reserve) back to the owner's associated token account
+    Withdraw {
+        /// USDC to withdraw
+        #[clap(long, conflicts_with = "all", required_unless_present = "all")]
+        amount: Option<f64>,
+        /// withdraw all free collateral above --reserve
+        #[clap(long, action)]
+        all: bool,
+        /// USDC of free collateral to leave behind with --all
+        #[clap(long, default_value_t = 0.0)]
+        reserve: f64,
+    },
+    /// replay a --record csv through the entry/exit + planning logic for each --min-net-apr x --holding-horizon-days
+    Backtest {
+        /// csv written by --record
+        #[clap(long)]
+        input: String,
+        /// min net APR %s to compare, eg. '0,5,10' (defaults to the top level --min-net-apr)
+        #[clap(long, value_delimiter = ',')]
+        min_net_apr: Vec<f64>,
+        /// holding horizons to compare in days, eg. '1,7,30' (defaults to the top level --holding-horizon-days)
+        #[clap(long, value_delimiter = ',')]
+        holding_horizon_days: Vec<f64>,
+        /// taker fee per leg in bps
+        #[clap(long, default_value_t = 10)]
+        fee_bps: u64,
+        /// perp slippage in bps (spot uses --spot-slippage-bps)
+        #[clap(long, default_value_t = 5)]
+        perp_slippage_bps: u64,
+    },
+}
 
-    simulate = !simulate; 
-    target_position_size *= BASE_PRECISION_U64 / 10;
+// how long to wait before retrying a failed pass in run mode
+const RETRY_INTERVAL_SECONDS: i64 = 60;
 
-    // setup rpc 
-    let cluster_name = "mainnet".to_string(); 
-    let cluster = match cluster_name.as_str() {
-        "mainnet" => Cluster::Mainnet, 
-        _ => panic!("not supported")
-    };
-    let connection_url = cluster.url();
-    let connection = RpcClient::new_with_commitment(
-        connection_url,
-        CommitmentConfig::confirmed()
-    );
-    
-    // setup anchor things 
-    let owner = read_keypair_file(keypair_path).unwrap();
-    let rc_owner = Rc::new(owner); 
-    let provider = Client::new_with_options(
-        cluster.clone(), 
-        rc_owner.clone(), 
-        CommitmentConfig::confirmed() 
-    );
-    let program = provider.program(*PROGRAM_ID);
-
-    // cache perp/spot data once for addresses 
-    // cache markets once to re-use in get_remaining_accounts
-    let state = get_state_public_key(&PROGRAM_ID);
-    let state_account = get_state(&connection, &state).unwrap();
-
-    let cached_accounts = get_cached_accounts(&connection, &state_account)?;
-    let remaining_accounts = get_remaining_accounts(&state_account, &cached_accounts)?;
-
-    let perp_address = get_perp_market_public_key(perp_market_index, &PROGRAM_ID);
-    let mut perp_market = *cast!(cached_accounts.get(&perp_address).unwrap(), Market::PerpMarket);
-
-    let spot_address = get_spot_market_public_key(spot_market_index, &PROGRAM_ID);
-    let spot_market = *cast!(cached_accounts.get(&spot_address).unwrap(), Market::SpotMarket);
-
-    let spot_name = String::from_utf8_lossy(&spot_market.name);
-    let perp_name = String::from_utf8_lossy(&perp_market.name);
-    let _spot = spot_name.trim();
-    let _perp = perp_name.trim().split('-').collect::<Vec<&str>>()[0];
-    println!("spot/perp name: {} {}", _spot, _perp);
-    if _spot != _perp {
-        println!("spot/perp name dont match ... exiting");
-        return Ok(())
+fn unix_timestamp() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+fn parse_interval(s: &str) -> Result<i64, String> {
+    let interval: i64 = s.parse().map_err(|_| format!("invalid interval {}", s))?;
+    if interval < 1 {
+        return Err(format!("interval must be at least 1 second, got {}", interval));
     }
+    Ok(interval)
+}
 
-    // 1e9 precision
-    let (funding_payment, funding_direction) = compute_funding_rate(&connection, &mut perp_market).unwrap();
-    println!("funding APR: {:#?} {:#?}", funding_payment, funding_direction);
+// next pass lands on the funding schedule: last_funding_rate_ts + k * interval, strictly after now
+// (interval > 0: --interval is validated and drift's funding period is always set)
+fn next_pass_ts(now: i64, last_funding_rate_ts: i64, interval: i64) -> i64 {
+    let periods_elapsed = (now - last_funding_rate_ts).div_euclid(interval);
+    last_funding_rate_ts + (periods_elapsed + 1) * interval
+}
 
-    // 1e9 precision
-    let borrow_rate = compute_borrow_rate(&spot_market).unwrap().mul(10_u128.pow(5_u32));
-    println!("borrow APR: {:#?}", borrow_rate);
+// sleeps in small steps so a shutdown signal is picked up quickly
+fn sleep_until(ts: i64, shutdown: &AtomicBool) {
+    while !shutdown.load(Ordering::SeqCst) && unix_timestamp() < ts {
+        thread::sleep(Duration::from_secs(1));
+    }
+}
 
-    // todo: check if greater than some threshold (to ensure profit)
-    let delta = funding_payment.saturating_sub(borrow_rate);
-    println!("INFO: funding delta % {}", delta as f64 / 1e9);
+// only trading needs a signer + size
+fn required<T>(value: Option<T>, flag: &str) -> T {
+    value.unwrap_or_else(|| {
+        Args::command()
+            .error(ErrorKind::MissingRequiredArgument, format!("{} is required", flag))
+            .exit()
+    })
+}
 
-    let should_close_position = delta == 0 || close;
-    if delta == 0 { 
-        println!("borrow rate too expensive to arb... closing positions");
-    } else if should_close_position { 
-        println!("closing positions...");
+// one pass of the arb: refresh markets, check funding vs borrow, rebalance if needed,
+// delever if health is too low
+fn run_arb(engine: &mut ArbEngine, portfolio: &Option<(Vec<MarketPair>, AllocationPolicy)>) -> Result<()> {
+    let (markets, rebalanced) = match portfolio {
+        Some((markets, policy)) => (markets.clone(), engine.rebalance_portfolio(markets, *policy).map(|_| ())),
+        None => {
+            let config = engine.config();
+            let markets = vec![MarketPair {
+                perp_market_index: config.perp_market_index,
+                spot_market_index: config.spot_market_index,
+            }];
+            let rebalanced = engine.evaluate().and_then(|opportunity| engine.rebalance(&opportunity)).map(|_| ());
+            (markets, rebalanced)
+        }
+    };
+    // a failed pass (eg. an invalid oracle or a rejected order) still delevers
+    let delevered = engine.delever(&markets);
+    rebalanced?;
+    delevered?;
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        println!("ERROR: {}", e);
+        process::exit(e.exit_code());
     }
+}
 
-    let user_address = get_user_public_key(&rc_owner.pubkey(), subaccount_id, &PROGRAM_ID);
-    let user = get_user(&connection, &user_address)?;
+fn run() -> Result<()> {
+    let Args {
+        cluster,
+        rpc_url,
+        program_id,
+        commitment,
+        keypair_path,
+        subaccount_id,
+        perp_market_index,
+        spot_market_index,
+        target_position_size,
+        notional_usd,
+        collateral_pct,
+        mut simulate,
+        close,
+        min_net_apr,
+        exit_apr,
+        min_hold_hours,
+        flip_confirmations,
+        market_policy,
+        holding_horizon_days,
+        spot_slippage_bps,
+        funding_horizon_hours,
+        record,
+        auction_duration,
+        max_slippage_bps,
+        delta_tolerance,
+        max_reconcile_attempts,
+        min_health,
+        delever_health,
+        max_leverage,
+        max_margin_utilization,
+        markets,
+        allocation,
+        max_market_allocation,
+        command,
+    } = Args::parse();
 
-    let (target_perp_position, target_spot_position) = match funding_direction { 
-        PositionDirection::Long => (PositionDirection::Long, SpotBalanceType::Borrow), 
-        PositionDirection::Short => (PositionDirection::Short, SpotBalanceType::Deposit), 
-    };
-    println!("target perp/spot positions: {:#?} {:#?}", target_perp_position, target_spot_position);
-
-    // adjust position
-    // base_amount = if we have a position: 
-        // if direction != target_direction: 
-            // close current + open in target direction:
-            // abs(position) + target position
-        // else
-            // do nothing 
-            // 0
-    // else 
-        // target position
-
-    let perp_order = if let Ok(position) = user.get_perp_position(perp_market_index) { 
-        if should_close_position {
-            Some((
-                position.base_asset_amount.unsigned_abs(), 
-                direction_to_close_position(position.base_asset_amount.into())
-            ))
-        } else if position.base_asset_amount != 0 && position.get_direction() != target_perp_position {
-            println!("PERP: closing current position: {:#?}", position);
-            Some((
-                position.base_asset_amount.unsigned_abs() + target_position_size, 
-                target_perp_position
-            ))
-        } else { 
-            println!("PERP: in correct position, doing nothing...");
-            None
-        }
-    } else { 
-        println!("PERP: no current position...");
-        Some((target_position_size, target_perp_position))
+    simulate = !simulate;
+    let bps = |x: u64| x as u128 * PERCENTAGE_PRECISION / 10_000;
+    let policy = PolicyConfig {
+        entry_net_apr: (min_net_apr.max(0.0) * 1e9) as u128,
+        exit_apr: (exit_apr * 1e9) as i128,
+        min_hold_seconds: (min_hold_hours * 3600.0) as i64,
+        flip_confirmations,
     };
 
-    if let Some((order_base_amount, direction)) = perp_order { 
-        let order_base_amount = standardize_base_asset_amount_ceil(
-            order_base_amount, 
-            perp_market.amm.order_step_size,
-        ).unwrap();
-
-        let params = get_order_params(
-            OrderType::Market,
-            MarketType::Perp,
-            direction,
-            order_base_amount,
-            perp_market_index,
-            false
-        );
-        println!("PERP: sending order...");
-
-        let req = program
-            .request()
-            .accounts(accounts::PlaceOrder { 
-                state, 
-                user: user_address, 
-                authority: rc_owner.pubkey()
-            })
-            .args(ix::PlacePerpOrder { 
-                params
-            }).accounts(remaining_accounts.clone());
-       
-        if !simulate { 
-            let sig = req.send().unwrap();
-            println!("sig {}", sig);
+    // offline -- no rpc or keypair needed
+    if let Some(Command::Backtest { input, min_net_apr: min_net_aprs, holding_horizon_days: holding_horizons, fee_bps, perp_slippage_bps }) = &command {
+        let target_position_size = required(target_position_size, "--target-position-size") * (BASE_PRECISION_U64 / 10);
+        let samples = read_samples(input)?;
+        println!("INFO: replaying {} samples from {}", samples.len(), input);
+
+        // the market's override (if any) with each entry threshold swept
+        let market_policies: HashMap<u16, PolicyConfig> = market_policy.iter().map(|p| (p.perp_market_index, p.config)).collect();
+        let policy = get_market_policy(&policy, &market_policies, perp_market_index);
+        let min_net_aprs = if min_net_aprs.is_empty() { vec![policy.entry_net_apr as f64 / 1e9] } else { min_net_aprs.clone() };
+        let holding_horizons = if holding_horizons.is_empty() { vec![holding_horizon_days] } else { holding_horizons.clone() };
+
+        let mut results = vec![];
+        for min_net_apr in min_net_aprs.iter() {
+            for holding_horizon_days in holding_horizons.iter() {
+                let config = BacktestConfig {
+                    perp_market_index,
+                    spot_market_index,
+                    target_position_size,
+                    holding_horizon_seconds: (holding_horizon_days * 86_400.0) as i64,
+                    policy: PolicyConfig {
+                        entry_net_apr: (min_net_apr.max(0.0) * 1e9) as u128,
+                        ..policy
+                    },
+                    perp_fee: bps(*fee_bps),
+                    spot_fee: bps(*fee_bps),
+                    perp_slippage: bps(*perp_slippage_bps),
+                    spot_slippage: bps(spot_slippage_bps),
+                };
+                results.push((config, run_backtest(&samples, &config)?));
+            }
         }
+        print_backtest(&results);
+        return Ok(());
     }
 
-    // adjust spot position
-    if !user.is_margin_trading_enabled {
-        println!("SPOT: enabling margin trading...");
-
-        // enable margin trading
-        let req = program
-            .request()
-            .accounts(accounts::UpdateUser {
-                user: user_address, 
-                authority: rc_owner.pubkey(),
-            })
-            .args(ix::UpdateUserMarginTradingEnabled {
-                _sub_account_id: subaccount_id, 
-                margin_trading_enabled: true
-            });
-
-        if !simulate { 
-            let sig = req.send().unwrap();
-            println!("sig {}", sig);
-        }
+    // setup rpc
+    let cluster = Cluster::Custom(
+        rpc_url.unwrap_or_else(|| cluster.url().to_string()),
+        cluster.ws_url().to_string(),
+    );
+    let program_id = program_id.unwrap_or(*PROGRAM_ID);
+    println!("INFO: rpc {} program {} ({:?})", cluster.url(), program_id, commitment.commitment);
+
+    let connection = RpcClient::new_with_commitment(
+        cluster.url().to_string(),
+        commitment
+    );
+
+    if let Some(Command::Scan) = command {
+        let state = get_state_public_key(&program_id);
+        let state_account = get_state(&connection, &state)?;
+        let cached_accounts = get_cached_accounts(&connection, &state_account, &program_id)?;
+
+        let pairs = scan_markets(&connection, &state_account, &cached_accounts, &program_id)?;
+        print_scan(&pairs);
+        return Ok(());
     }
 
-    let spot_order = if let Some(position) = user.get_spot_position(spot_market_index) { 
-        let token_amount = position.get_signed_token_amount(&spot_market).unwrap();
-
-        if should_close_position { 
-            let direction_to_close = match target_spot_position { 
-                SpotBalanceType::Borrow => PositionDirection::Long, 
-                SpotBalanceType::Deposit => PositionDirection::Short,
-            };
-            Some((
-                token_amount.unsigned_abs() as u64, 
-                direction_to_close
-            ))
-        } else if position.scaled_balance != 0 && position.balance_type != target_spot_position { 
-            println!("SPOT: closing current position: {:#?}", position);
-            let direction = match target_spot_position { 
-                SpotBalanceType::Borrow => PositionDirection::Short, 
-                SpotBalanceType::Deposit => PositionDirection::Long,
-            };
-            Some((token_amount.unsigned_abs() as u64 + target_position_size, direction))
-        } else { 
-            println!("SPOT: in correct position, doing nothing...");
-            None
-        }
-    } else { 
-        let direction = match target_spot_position { 
-            SpotBalanceType::Borrow => PositionDirection::Short, 
-            SpotBalanceType::Deposit => PositionDirection::Long,
-        };
-        println!("SPOT: no current position...");
-        Some((target_position_size, direction))
+    let portfolio = if markets.is_empty() {
+        None
+    } else {
+        let max_market_allocation = max_market_allocation.map(|x| (x * QUOTE_PRECISION as f64) as u128);
+        let policy = AllocationPolicy::from_name(&allocation, max_market_allocation).unwrap_or_else(|| {
+            Args::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    format!("invalid --allocation {} (expected equal, carry or capped with --max-market-allocation)", allocation),
+                )
+                .exit()
+        });
+        Some((markets, policy))
     };
 
-    if let Some((spot_order_size, direction)) = spot_order { 
-        let spot_order_size = standardize_base_asset_amount_ceil(
-            spot_order_size, 
-            spot_market.order_step_size
-        ).unwrap();
-
-        let params = get_order_params(
-            OrderType::Market,
-            MarketType::Spot,
-            direction,
-            spot_order_size,
-            spot_market_index,
-            false
-        );
-        println!("SPOT: sending order...");
-
-        let req = program
-            .request()
-            .accounts(accounts::PlaceOrder { 
-                state, 
-                user: user_address, 
-                authority: rc_owner.pubkey()
-            })
-            .args(ix::PlaceSpotOrder { 
-                params
-            }).accounts(remaining_accounts);
-        
-        if !simulate { 
-            let sig = req.send().unwrap();
-            println!("sig {}", sig);
+    let keypair_path = required(keypair_path, "--keypair-path");
+    // portfolio sizes come from the allocation policy
+    let target_position_size = match (target_position_size, notional_usd, collateral_pct) {
+        (Some(size), _, _) => PositionSize::Base(size * (BASE_PRECISION_U64 / 10)),
+        (_, Some(usd), _) => PositionSize::Notional((usd * QUOTE_PRECISION as f64) as u128),
+        (_, _, Some(pct)) => PositionSize::FreeCollateral((pct * 1e4) as u128),
+        _ if portfolio.is_some() || matches!(command, Some(Command::Init { .. }) | Some(Command::Withdraw { .. })) => PositionSize::Base(0),
+        _ => required(None, "--target-position-size, --notional-usd or --collateral-pct"),
+    };
+
+    let owner = read_keypair_file(&keypair_path)
+        .map_err(|e| DriftError::KeypairError(format!("{}: {}", keypair_path, e)))?;
+    let signer: Rc<dyn Signer> = Rc::new(owner);
+
+    let config = ArbConfig {
+        program_id,
+        subaccount_id,
+        perp_market_index,
+        spot_market_index,
+        target_position_size,
+        simulate,
+        close,
+        profitability: ProfitabilityConfig {
+            holding_horizon_seconds: (holding_horizon_days * 86_400.0) as i64,
+            spot_slippage: bps(spot_slippage_bps),
+        },
+        auction: AuctionConfig {
+            auction_duration,
+            max_slippage: bps(max_slippage_bps),
+        },
+        funding_horizon_seconds: funding_horizon_hours.map(|hours| (hours * 3600.0) as i64),
+        delta_tolerance: (delta_tolerance * BASE_PRECISION as f64) as u64,
+        max_reconcile_attempts,
+        policy,
+        min_health: (min_health * 1e4) as u128,
+        delever_health: (delever_health.max(min_health) * 1e4) as u128,
+        sizing: SizingConfig {
+            max_leverage: max_leverage.map(|x| (x * PERCENTAGE_PRECISION as f64) as u128),
+            max_margin_utilization: (max_margin_utilization * 1e4) as u128,
+        },
+    };
+    let mut engine = ArbEngine::new(connection, signer, config).with_market_policies(&market_policy);
+    if let Some(path) = record {
+        engine = engine.with_recorder(Recorder::new(path));
+    }
+
+    match command {
+        None => {
+            run_arb(&mut engine, &portfolio)?;
+        }
+        Some(Command::Run { interval }) => {
+            let shutdown = Arc::new(AtomicBool::new(false));
+            let signal = shutdown.clone();
+            ctrlc::set_handler(move || {
+                println!("shutdown requested, exiting after the current pass...");
+                signal.store(true, Ordering::SeqCst);
+            }).expect("failed to install SIGINT/SIGTERM handler");
+
+            while !shutdown.load(Ordering::SeqCst) {
+                let pass = run_arb(&mut engine, &portfolio).and_then(|_| engine.perp_market());
+                // from when the pass finished so one that overran skips to the next slot
+                let now = unix_timestamp();
+                let next_ts = match pass {
+                    Ok(perp_market) => {
+                        let interval = interval.unwrap_or(perp_market.amm.funding_period);
+                        next_pass_ts(now, perp_market.amm.last_funding_rate_ts, interval)
+                    }
+                    Err(e) => {
+                        println!("ERROR: pass failed: {} ... retrying in {}s", e, RETRY_INTERVAL_SECONDS);
+                        now + RETRY_INTERVAL_SECONDS
+                    }
+                };
+                println!("INFO: next pass in {}s", next_ts - unix_timestamp());
+                sleep_until(next_ts, &shutdown);
+            }
+            println!("shutdown complete");
         }
+        Some(Command::Init { deposit }) => {
+            engine.initialize(deposit.map(|usdc| (usdc * QUOTE_PRECISION as f64) as u64))?;
+        }
+        Some(Command::Withdraw { amount, all, reserve }) => {
+            let usdc = |x: f64| (x * QUOTE_PRECISION as f64) as u64;
+            let amount = if all { None } else { amount.map(usdc) };
+            engine.withdraw(amount, usdc(reserve))?;
+        }
+        Some(Command::Scan) | Some(Command::Backtest { .. }) => unreachable!(),
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: i64 = 3_600;
+    const LAST_FUNDING_TS: i64 = 1_670_000_400;
+
+    #[test]
+    fn next_pass_aligns_to_the_funding_schedule() {
+        assert_eq!(next_pass_ts(LAST_FUNDING_TS + 100, LAST_FUNDING_TS, HOUR), LAST_FUNDING_TS + HOUR);
+        // exactly on a funding update -> the next one
+        assert_eq!(next_pass_ts(LAST_FUNDING_TS, LAST_FUNDING_TS, HOUR), LAST_FUNDING_TS + HOUR);
+        // shorter interval, still counted from the last funding update
+        assert_eq!(next_pass_ts(LAST_FUNDING_TS + 700, LAST_FUNDING_TS, 600), LAST_FUNDING_TS + 1_200);
+        // the market's funding ts lagging the clock
+        assert_eq!(next_pass_ts(LAST_FUNDING_TS - 100, LAST_FUNDING_TS, HOUR), LAST_FUNDING_TS);
+    }
+
+    #[test]
+    fn overrunning_pass_skips_to_the_next_slot() {
+        // a pass that finished 2.5 intervals after the last funding update skips the missed slots
+        assert_eq!(next_pass_ts(LAST_FUNDING_TS + 5 * HOUR / 2, LAST_FUNDING_TS, HOUR), LAST_FUNDING_TS + 3 * HOUR);
+        assert_eq!(next_pass_ts(LAST_FUNDING_TS + 3 * HOUR, LAST_FUNDING_TS, HOUR), LAST_FUNDING_TS + 4 * HOUR);
+    }
+
+    #[test]
+    fn interval_must_be_positive() {
+        assert_eq!(parse_interval("600"), Ok(600));
+        assert!(parse_interval("0").is_err());
+        assert!(parse_interval("-60").is_err());
+        assert!(parse_interval("ten").is_err());
+    }
+}
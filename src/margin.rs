@@ -0,0 +1,105 @@
+use std::collections::BTreeSet;
+
+use anchor_client::solana_client::rpc_client::RpcClient;
+use anchor_client::anchor_lang::prelude::AccountInfo;
+use anchor_client::solana_sdk::account::Account;
+use anchor_client::solana_sdk::pubkey::Pubkey;
+
+use drift::math::margin::{calculate_margin_requirement_and_total_collateral, MarginRequirementType};
+use drift::state::oracle_map::OracleMap;
+use drift::state::perp_market_map::PerpMarketMap;
+use drift::state::spot_market_map::SpotMarketMap;
+use drift::state::user::User;
+
+use crate::constants::*;
+
+// get_multiple_accounts limit
+const MAX_MULTIPLE_ACCOUNTS: usize = 100;
+
+// raw account kept around so drift's market/oracle maps can borrow it as an AccountInfo
+pub struct AccountSnapshot {
+    pub key: Pubkey,
+    pub owner: Pubkey,
+    pub lamports: u64,
+    pub data: Vec<u8>,
+}
+
+impl AccountSnapshot {
+    pub fn new(key: Pubkey, account: Account) -> Self {
+        AccountSnapshot {
+            key,
+            owner: account.owner,
+            lamports: account.lamports,
+            data: account.data,
+        }
+    }
+
+    pub fn to_account_info(&mut self) -> AccountInfo<'_> {
+        AccountInfo::new(
+            &self.key,
+            false,
+            false,
+            &mut self.lamports,
+            &mut self.data,
+            &self.owner,
+            false,
+            0
+        )
+    }
+}
+
+pub fn get_account_snapshots(connection: &RpcClient, keys: &[Pubkey]) -> Result<Vec<AccountSnapshot>> {
+    let mut snapshots = vec![];
+    for chunk in keys.chunks(MAX_MULTIPLE_ACCOUNTS) {
+        let accounts = connection.get_multiple_accounts(chunk)?;
+        for (key, account) in chunk.iter().zip(accounts) {
+            if let Some(account) = account {
+                snapshots.push(AccountSnapshot::new(*key, account));
+            }
+        }
+    }
+    Ok(snapshots)
+}
+
+// QUOTE_PRECISION
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MarginInfo {
+    pub total_collateral: i128,
+    pub margin_requirement: u128,
+    pub free_collateral: u128,
+}
+
+// snapshots must be ordered like get_remaining_accounts: oracles, spot markets, perp markets
+pub fn calculate_margin(
+    user: &User,
+    snapshots: &mut [AccountSnapshot],
+    slot: u64,
+    margin_requirement_type: MarginRequirementType,
+) -> Result<MarginInfo> {
+    let account_infos: Vec<AccountInfo> = snapshots.iter_mut().map(|snapshot| snapshot.to_account_info()).collect();
+    let account_info_iter = &mut account_infos.iter().peekable();
+
+    let mut oracle_map = OracleMap::load(account_info_iter, slot, None)?;
+    let spot_market_map = SpotMarketMap::load(&BTreeSet::new(), account_info_iter)?;
+    let perp_market_map = PerpMarketMap::load(&BTreeSet::new(), account_info_iter)?;
+
+    let (margin_requirement, total_collateral, _, _) = calculate_margin_requirement_and_total_collateral(
+        user,
+        &perp_market_map,
+        margin_requirement_type,
+        &spot_market_map,
+        &mut oracle_map,
+        None,
+    )?;
+
+    let free_collateral = total_collateral
+        .max(0)
+        .unsigned_abs()
+        .saturating_sub(margin_requirement);
+
+    Ok(MarginInfo {
+        total_collateral,
+        margin_requirement,
+        free_collateral,
+    })
+}
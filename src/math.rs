@@ -7,70 +7,103 @@ use drift::controller::position::PositionDirection;
 use drift::error::DriftResult;
 use drift::math::casting::Cast;
 use drift::math::constants::*;
+use drift::math::helpers::on_the_hour_update;
+use drift::math::oracle::{is_oracle_valid_for_action, oracle_validity, DriftAction, OracleValidity};
 use drift::math::safe_math::SafeMath;
 use drift::math::spot_balance::{get_token_amount};
-use drift::state::oracle::OraclePriceData;
+use drift::state::oracle::{get_oracle_price, OraclePriceData, OracleSource};
 use drift::state::perp_market::PerpMarket;
 use drift::state::spot_market::{SpotMarket};
 use drift::state::spot_market::SpotBalanceType;
+use drift::state::state::ValidityGuardRails;
+
+use crate::constants::{DriftError, Result};
+use crate::margin::AccountSnapshot;
+
+// v2/state/oracle.rs -- dispatches on the oracle source (pyth, pyth 1K/1M, pyth stable coin,
+// switchboard, quote asset) and scales the price to PRICE_PRECISION
+pub fn get_oracle_info(connection: &RpcClient, oracle_pk: &Pubkey, oracle_source: OracleSource) -> Result<OraclePriceData> {
+    // the quote asset has no oracle account
+    if oracle_source == OracleSource::QuoteAsset {
+        return Ok(OraclePriceData {
+            price: PRICE_PRECISION_I64,
+            confidence: 1,
+            delay: 0,
+            has_sufficient_number_of_data_points: true,
+        });
+    }
 
-use pyth_sdk_solana::{load_price_feed_from_account, PriceFeed, Price};
+    let account = connection.get_account(oracle_pk)?;
+    let slot = connection.get_slot()?;
 
-fn get_oracle_info(connection: &RpcClient, oracle_pk: &Pubkey) -> DriftResult<OraclePriceData> {
-    let mut account = connection.get_account(oracle_pk).unwrap();
-    let price_feed: PriceFeed = load_price_feed_from_account(oracle_pk, &mut account).unwrap();
-    let price_data: Price = price_feed.get_current_price().unwrap();
-    let oracle_price = price_data.price;
-    let oracle_conf = price_data.conf;
+    let mut snapshot = AccountSnapshot::new(*oracle_pk, account);
+    let account_info = snapshot.to_account_info();
+    get_oracle_price(&oracle_source, &account_info, slot)
+        .map_err(|e| DriftError::OracleInvalid(*oracle_pk, format!("{:?} ({:?} oracle)", e, oracle_source)))
+}
 
-    let oracle_precision = 10_u128.pow(price_data.expo.unsigned_abs());
+pub fn get_perp_oracle_info(connection: &RpcClient, perp_market: &PerpMarket) -> Result<OraclePriceData> {
+    get_oracle_info(connection, &perp_market.amm.oracle, perp_market.amm.oracle_source)
+}
 
-    let mut oracle_scale_mult = 1;
-    let mut oracle_scale_div = 1;
+pub fn get_spot_oracle_info(connection: &RpcClient, spot_market: &SpotMarket) -> Result<OraclePriceData> {
+    get_oracle_info(connection, &spot_market.oracle, spot_market.oracle_source)
+}
 
-    if oracle_precision > PRICE_PRECISION {
-        oracle_scale_div = oracle_precision.safe_div(PRICE_PRECISION)?;
-    } else {
-        oracle_scale_mult = PRICE_PRECISION.safe_div(oracle_precision)?;
+// v2/math/oracle.rs -- same validity rules drift applies on-chain (publish slot delay,
+// confidence / price ratio, divergence from the oracle twap)
+pub fn check_oracle_validity(
+    oracle_pk: &Pubkey,
+    oracle_price_data: &OraclePriceData,
+    last_oracle_price_twap: i64,
+    guard_rails: &ValidityGuardRails,
+    action: DriftAction,
+) -> Result<()> {
+    let validity = oracle_validity(last_oracle_price_twap, oracle_price_data, guard_rails)?;
+    if is_oracle_valid_for_action(validity, Some(action))? {
+        return Ok(());
     }
 
-    let oracle_price_scaled = (oracle_price)
-        .cast::<i128>()?
-        .safe_mul(oracle_scale_mult.cast()?)?
-        .safe_div(oracle_scale_div.cast()?)?
-        .cast::<i64>()?;
-
-    let oracle_conf_scaled = (oracle_conf)
-        .cast::<u128>()?
-        .safe_mul(oracle_scale_mult)?
-        .safe_div(oracle_scale_div)?
-        .cast::<u64>()?;
-
-    Ok(OraclePriceData {
-        price: oracle_price_scaled,
-        confidence: oracle_conf_scaled,
-        delay: 0,
-        has_sufficient_number_of_data_points: true,
-    })
+    let reason = format!(
+        "{:?} for {:?} (price {} confidence {} delay {} slots twap {})",
+        validity,
+        action,
+        oracle_price_data.price,
+        oracle_price_data.confidence,
+        oracle_price_data.delay,
+        last_oracle_price_twap,
+    );
+    match validity {
+        OracleValidity::StaleForMargin | OracleValidity::StaleForAMM => Err(DriftError::OracleStale(*oracle_pk, reason)),
+        _ => Err(DriftError::OracleInvalid(*oracle_pk, reason)),
+    }
 }
 
 // v2/controller/funding.rs
 // v2/math/funding.rs
-pub fn compute_funding_rate(connection: &RpcClient, market: &mut PerpMarket) -> DriftResult<(u128, PositionDirection)> { 
-    let oracle_pk = market.amm.oracle;
-    let oracle_price_data = get_oracle_info(connection, &oracle_pk).unwrap();
-    let sanitize_clamp_denominator = market.get_sanitize_clamp_denominator().unwrap();
-
-    let slot = connection.get_slot().unwrap();
-    let now = connection.get_block_time(slot).unwrap();
-    let reserve_price = market.amm.reserve_price().unwrap();
+pub fn compute_funding_rate(connection: &RpcClient, market: &mut PerpMarket) -> Result<(u128, PositionDirection)> { 
+    let oracle_price_data = get_perp_oracle_info(connection, market)?;
+    let slot = connection.get_slot()?;
+    let now = connection.get_block_time(slot)?;
+
+    Ok(compute_funding_rate_at(market, &oracle_price_data, now)?)
+}
+
+// funding accruing as of now (APR % in 1e9 precision) + the side receiving it
+pub fn compute_funding_rate_at(
+    market: &mut PerpMarket,
+    oracle_price_data: &OraclePriceData,
+    now: i64,
+) -> DriftResult<(u128, PositionDirection)> {
+    let sanitize_clamp_denominator = market.get_sanitize_clamp_denominator()?;
+    let reserve_price = market.amm.reserve_price()?;
     let oracle_price_twap = drift::math::amm::update_oracle_price_twap(
         &mut market.amm,
         now,
-        &oracle_price_data,
+        oracle_price_data,
         Some(reserve_price),
         sanitize_clamp_denominator,
-    ).unwrap();
+    )?;
 
     // price relates to execution premium / direction
     let (execution_premium_price, execution_premium_direction) =
@@ -138,8 +171,146 @@ pub fn compute_funding_rate(connection: &RpcClient, market: &mut PerpMarket) ->
     Ok((funding_apr, funding_direction))
 }
 
+// funding rate per period (FUNDING_RATE_PRECISION, + = longs pay shorts) -> APR % in 1e9 precision
+pub fn funding_rate_to_apr(funding_rate: i128, oracle_price_twap: i64, funding_period: i64) -> DriftResult<i128> {
+    funding_rate
+        .safe_mul(PRICE_PRECISION_I128)?
+        .safe_div(oracle_price_twap.cast()?)?
+        .safe_mul(100)?
+        .safe_mul(ONE_YEAR.cast()?)?
+        .safe_div(max(funding_period, 1).cast()?)
+}
+
+// APR % in 1e9 precision -- signed rates are from the side receiving the predicted funding
+#[derive(Debug, Clone, Copy)]
+pub struct FundingProjection {
+    // as of
+    pub slot: u64,
+    pub ts: i64,
+    pub direction: PositionDirection,
+    // accruing now, paid at the next funding update
+    pub predicted_apr: u128,
+    // last settled rate
+    pub last_apr: i128,
+    pub last_24h_avg_apr: i128,
+    pub seconds_to_next_funding: i64,
+    pub horizon_seconds: i64,
+    // expected over the horizon: the next payment at the predicted rate, the rest at the 24h avg
+    pub blended_apr: i128,
+}
 
-pub fn compute_borrow_rate(spot_market: &SpotMarket) -> DriftResult<u128> {
+// funding APR (+ = longs pay shorts) as seen by the side receiving the predicted funding
+pub fn to_received_apr(apr: i128, direction: PositionDirection) -> i128 {
+    match direction {
+        PositionDirection::Short => apr,
+        PositionDirection::Long => -apr,
+    }
+}
+
+// expected APR over the horizon: the next payment at the predicted rate, the rest at the 24h avg
+// -- 0 when no payment lands within it
+pub fn blend_funding_apr(
+    predicted_apr: u128,
+    last_24h_avg_apr: i128,
+    seconds_to_next_funding: i64,
+    funding_period: i64,
+    horizon_seconds: i64,
+) -> DriftResult<i128> {
+    let funding_period = max(funding_period, 1);
+    let horizon_seconds = max(horizon_seconds, 1);
+
+    // payments landing within the horizon
+    let payments = if seconds_to_next_funding > horizon_seconds {
+        0
+    } else {
+        horizon_seconds
+            .safe_sub(seconds_to_next_funding)?
+            .safe_div(funding_period)?
+            .safe_add(1)?
+    };
+
+    let next_payment = if payments > 0 { predicted_apr.cast::<i128>()? } else { 0 };
+    let later_payments = last_24h_avg_apr.safe_mul(max(payments - 1, 0).cast()?)?;
+    next_payment
+        .safe_add(later_payments)?
+        .safe_mul(funding_period.cast()?)?
+        .safe_div(horizon_seconds.cast()?)
+}
+
+pub fn project_funding(
+    market: &mut PerpMarket,
+    oracle_price_data: &OraclePriceData,
+    slot: u64,
+    now: i64,
+    horizon_seconds: i64,
+) -> DriftResult<FundingProjection> {
+    let (predicted_apr, direction) = compute_funding_rate_at(market, oracle_price_data, now)?;
+
+    let funding_period = max(market.amm.funding_period, 1);
+    let oracle_price_twap = market.amm.historical_oracle_data.last_oracle_price_twap;
+    let last_apr = to_received_apr(
+        funding_rate_to_apr(market.amm.last_funding_rate.cast()?, oracle_price_twap, funding_period)?,
+        direction,
+    );
+    let last_24h_avg_apr = to_received_apr(
+        funding_rate_to_apr(market.amm.last_24h_avg_funding_rate.cast()?, oracle_price_twap, funding_period)?,
+        direction,
+    );
+
+    let seconds_to_next_funding = on_the_hour_update(now, market.amm.last_funding_rate_ts, funding_period)?;
+    let horizon_seconds = max(horizon_seconds, 1);
+    let blended_apr = blend_funding_apr(
+        predicted_apr,
+        last_24h_avg_apr,
+        seconds_to_next_funding,
+        funding_period,
+        horizon_seconds,
+    )?;
+
+    Ok(FundingProjection {
+        slot,
+        ts: now,
+        direction,
+        predicted_apr,
+        last_apr,
+        last_24h_avg_apr,
+        seconds_to_next_funding,
+        horizon_seconds,
+        blended_apr,
+    })
+}
+
+pub fn compute_funding_projection(
+    connection: &RpcClient,
+    market: &mut PerpMarket,
+    horizon_seconds: i64,
+) -> Result<FundingProjection> {
+    let oracle_price_data = get_perp_oracle_info(connection, market)?;
+    let slot = connection.get_slot()?;
+    let now = connection.get_block_time(slot)?;
+
+    Ok(project_funding(market, &oracle_price_data, slot, now, horizon_seconds)?)
+}
+
+pub fn print_funding_projection(projection: &FundingProjection) {
+    println!(
+        "FUNDING: predicted APR % {} ({:?} receives) next funding in {}s",
+        projection.predicted_apr as f64 / 1e9,
+        projection.direction,
+        projection.seconds_to_next_funding,
+    );
+    println!(
+        "FUNDING: last APR % {} 24h avg APR % {} blended APR % {} (over {} hours)",
+        projection.last_apr as f64 / 1e9,
+        projection.last_24h_avg_apr as f64 / 1e9,
+        projection.blended_apr as f64 / 1e9,
+        projection.horizon_seconds as f64 / 3600.0,
+    );
+}
+
+
+// SPOT_UTILIZATION_PRECISION
+pub fn compute_utilization(spot_market: &SpotMarket) -> DriftResult<u128> {
     let deposit_token_amount = get_token_amount(
         spot_market.deposit_balance,
         spot_market,
@@ -151,7 +322,11 @@ pub fn compute_borrow_rate(spot_market: &SpotMarket) -> DriftResult<u128> {
         &SpotBalanceType::Borrow,
     )?;
 
-    let utilization = drift::math::spot_balance::calculate_utilization(deposit_token_amount, borrow_token_amount)?;
+    drift::math::spot_balance::calculate_utilization(deposit_token_amount, borrow_token_amount)
+}
+
+pub fn compute_borrow_rate(spot_market: &SpotMarket) -> DriftResult<u128> {
+    let utilization = compute_utilization(spot_market)?;
 
     if utilization == 0 {
         return Ok(0);
@@ -187,4 +362,170 @@ pub fn compute_borrow_rate(spot_market: &SpotMarket) -> DriftResult<u128> {
     };
 
     Ok(borrow_rate)
-}
\ No newline at end of file
+}
+
+// depositors earn the borrow interest pro-rata to utilization, minus the
+// insurance fund / revenue pool cut (v2/math/insurance.rs)
+pub fn compute_deposit_rate(spot_market: &SpotMarket) -> DriftResult<u128> {
+    let utilization = compute_utilization(spot_market)?;
+    let borrow_rate = compute_borrow_rate(spot_market)?;
+
+    let depositor_factor = IF_FACTOR_PRECISION
+        .saturating_sub(spot_market.insurance_fund.total_factor.cast()?);
+
+    borrow_rate
+        .safe_mul(utilization)?
+        .safe_div(SPOT_UTILIZATION_PRECISION)?
+        .safe_mul(depositor_factor)?
+        .safe_div(IF_FACTOR_PRECISION)
+}
+
+// carry of the arb before costs (APR % in 1e9 precision) for a funding direction:
+//   long perp -> borrow spot (funding - the borrow rate)
+//   short perp -> deposit spot (funding + the deposit rate)
+// funding_apr is what the direction's side receives (negative when it's expected to pay)
+pub fn gross_apr(
+    funding_apr: i128,
+    funding_direction: PositionDirection,
+    borrow_apr: u128,
+    deposit_apr: u128,
+) -> DriftResult<i128> {
+    match funding_direction {
+        PositionDirection::Long => funding_apr.safe_sub(borrow_apr.cast()?),
+        PositionDirection::Short => funding_apr.safe_add(deposit_apr.cast()?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONE_PERCENT: u128 = 1_000_000_000;
+    const PERIOD: i64 = 3_600;
+
+    #[test]
+    fn gross_apr_pays_borrow_long_and_earns_deposit_short() {
+        let gross = |direction| gross_apr(10 * ONE_PERCENT as i128, direction, 4 * ONE_PERCENT, ONE_PERCENT).unwrap();
+        assert_eq!(gross(PositionDirection::Long), 6 * ONE_PERCENT as i128);
+        assert_eq!(gross(PositionDirection::Short), 11 * ONE_PERCENT as i128);
+        // borrowing costs more than funding pays
+        assert_eq!(
+            gross_apr(ONE_PERCENT as i128, PositionDirection::Long, 4 * ONE_PERCENT, 0).unwrap(),
+            -3 * ONE_PERCENT as i128
+        );
+        // horizon expected to pay funding outweighs the deposit rate
+        assert_eq!(
+            gross_apr(-2 * ONE_PERCENT as i128, PositionDirection::Short, 0, ONE_PERCENT).unwrap(),
+            -ONE_PERCENT as i128
+        );
+    }
+
+    #[test]
+    fn horizon_shorter_than_a_period() {
+        // next payment lands after the horizon
+        assert_eq!(blend_funding_apr(10 * ONE_PERCENT, 0, 1_800, PERIOD, 600).unwrap(), 0);
+        // next payment lands within it and is all that's earned over 10 minutes
+        assert_eq!(
+            blend_funding_apr(10 * ONE_PERCENT, 0, 300, PERIOD, 600).unwrap(),
+            60 * ONE_PERCENT as i128
+        );
+    }
+
+    #[test]
+    fn horizon_of_exactly_one_period() {
+        assert_eq!(
+            blend_funding_apr(10 * ONE_PERCENT, 4 * ONE_PERCENT as i128, PERIOD, PERIOD, PERIOD).unwrap(),
+            10 * ONE_PERCENT as i128
+        );
+    }
+
+    #[test]
+    fn many_periods_weight_the_24h_avg() {
+        // 1 payment at 10% + 23 at 4%
+        assert_eq!(
+            blend_funding_apr(10 * ONE_PERCENT, 4 * ONE_PERCENT as i128, PERIOD, PERIOD, 24 * PERIOD).unwrap(),
+            (10 + 23 * 4) * ONE_PERCENT as i128 / 24
+        );
+        // horizon thats not a multiple of the period
+        assert_eq!(
+            blend_funding_apr(10 * ONE_PERCENT, 4 * ONE_PERCENT as i128, 1_800, PERIOD, 2 * PERIOD).unwrap(),
+            (10 + 4) * ONE_PERCENT as i128 / 2
+        );
+    }
+
+    #[test]
+    fn avg_paying_the_other_side_drags_the_blend_negative() {
+        // shorts receive the predicted 10%, longs received the last 24h (+ = longs pay shorts)
+        let last_24h_avg_apr = to_received_apr(-8 * ONE_PERCENT as i128, PositionDirection::Short);
+        assert_eq!(last_24h_avg_apr, -8 * ONE_PERCENT as i128);
+        assert_eq!(
+            blend_funding_apr(10 * ONE_PERCENT, last_24h_avg_apr, PERIOD, PERIOD, 24 * PERIOD).unwrap(),
+            (10 - 23 * 8) * ONE_PERCENT as i128 / 24
+        );
+
+        // same rates seen from longs receiving the predicted funding
+        let last_24h_avg_apr = to_received_apr(8 * ONE_PERCENT as i128, PositionDirection::Long);
+        assert_eq!(last_24h_avg_apr, -8 * ONE_PERCENT as i128);
+        assert!(blend_funding_apr(10 * ONE_PERCENT, last_24h_avg_apr, PERIOD, PERIOD, 24 * PERIOD).unwrap() < 0);
+    }
+
+    // 8% borrow rate at 80% utilization, 100% at full utilization
+    fn spot_market(deposits: u128, borrows: u128, total_factor: u32) -> SpotMarket {
+        let mut spot_market = SpotMarket {
+            decimals: 6,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            deposit_balance: deposits * SPOT_BALANCE_PRECISION,
+            borrow_balance: borrows * SPOT_BALANCE_PRECISION,
+            optimal_utilization: 800_000,
+            optimal_borrow_rate: 80_000,
+            max_borrow_rate: 1_000_000,
+            ..SpotMarket::default()
+        };
+        spot_market.insurance_fund.total_factor = total_factor;
+        spot_market
+    }
+
+    #[test]
+    fn deposit_rate_is_borrow_rate_times_utilization() {
+        // 50% utilization -> 5% borrow rate, depositors earn half of it
+        let market = spot_market(100, 50, 0);
+        assert_eq!(compute_utilization(&market).unwrap(), 500_000);
+        assert_eq!(compute_borrow_rate(&market).unwrap(), 50_000);
+        assert_eq!(compute_deposit_rate(&market).unwrap(), 25_000);
+
+        // above the optimal utilization: 90% -> 8% + 10% * (100% - 8%) / 20% = 54%
+        let market = spot_market(100, 90, 0);
+        assert_eq!(compute_borrow_rate(&market).unwrap(), 540_000);
+        assert_eq!(compute_deposit_rate(&market).unwrap(), 486_000);
+    }
+
+    #[test]
+    fn deposit_rate_takes_the_insurance_cut() {
+        // 10% of the interest goes to the insurance fund / revenue pool
+        let market = spot_market(100, 50, 100_000);
+        assert_eq!(compute_borrow_rate(&market).unwrap(), 50_000);
+        assert_eq!(compute_deposit_rate(&market).unwrap(), 22_500);
+    }
+
+    #[test]
+    fn zero_utilization_earns_nothing() {
+        assert_eq!(compute_deposit_rate(&spot_market(100, 0, 0)).unwrap(), 0);
+        assert_eq!(compute_deposit_rate(&spot_market(0, 0, 0)).unwrap(), 0);
+    }
+
+    #[test]
+    fn max_insurance_factor_leaves_depositors_nothing() {
+        let market = spot_market(100, 50, IF_FACTOR_PRECISION as u32);
+        assert_eq!(compute_borrow_rate(&market).unwrap(), 50_000);
+        assert_eq!(compute_deposit_rate(&market).unwrap(), 0);
+    }
+
+    #[test]
+    fn funding_rate_converts_to_apr() {
+        // 0.01 per 1.0 of base hourly at 100 = 0.01% per period = 87.6% APR
+        let funding_rate = FUNDING_RATE_PRECISION_I128 / 100;
+        assert_eq!(funding_rate_to_apr(funding_rate, 100 * PRICE_PRECISION_I64, PERIOD).unwrap(), 87_600_000_000);
+        assert_eq!(funding_rate_to_apr(-funding_rate, 100 * PRICE_PRECISION_I64, PERIOD).unwrap(), -87_600_000_000);
+    }
+}
@@ -0,0 +1,577 @@
+use drift::controller::position::PositionDirection;
+use drift::error::DriftResult;
+use drift::math::casting::Cast;
+use drift::math::constants::{BASE_PRECISION, PERCENTAGE_PRECISION};
+use drift::math::orders::{standardize_base_asset_amount, standardize_base_asset_amount_ceil};
+use drift::math::safe_math::SafeMath;
+use drift::state::perp_market::PerpMarket;
+use drift::state::spot_market::{SpotMarket, SpotBalanceType};
+use drift::state::user::{User, MarketType};
+
+// what the account should look like after rebalancing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RebalanceTarget {
+    // base precision (1e9)
+    pub base_asset_amount: u64,
+    pub perp_direction: PositionDirection,
+    pub spot_balance_type: SpotBalanceType,
+    // target is flat on both legs
+    pub close: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OrderIntent {
+    pub market_type: MarketType,
+    pub market_index: u16,
+    pub direction: PositionDirection,
+    // perp = base precision, spot = token precision
+    pub base_asset_amount: u64,
+    pub reduce_only: bool,
+}
+
+// perp base amounts are 1e9 but spot token amounts use the mint's decimals
+pub fn base_to_token_amount(base_asset_amount: u64, spot_market: &SpotMarket) -> DriftResult<u64> {
+    base_asset_amount
+        .cast::<u128>()?
+        .safe_mul(10_u128.pow(spot_market.decimals))?
+        .safe_div(BASE_PRECISION)?
+        .cast()
+}
+
+// spot token amount (mint decimals) -> perp base amount (1e9)
+pub fn token_to_base_amount(token_amount: i128, spot_market: &SpotMarket) -> DriftResult<i128> {
+    token_amount
+        .safe_mul(BASE_PRECISION.cast()?)?
+        .safe_div(10_i128.pow(spot_market.decimals))
+}
+
+fn signed_amount(amount: u64, direction: PositionDirection) -> DriftResult<i128> {
+    let amount = amount.cast::<i128>()?;
+    Ok(match direction {
+        PositionDirection::Long => amount,
+        PositionDirection::Short => -amount,
+    })
+}
+
+// diffs current vs target position (both signed) into a single order
+fn plan_leg(
+    market_type: MarketType,
+    market_index: u16,
+    current: i128,
+    target: i128,
+    step_size: u64,
+) -> DriftResult<Option<OrderIntent>> {
+    let diff = target.safe_sub(current)?;
+    if diff == 0 {
+        return Ok(None);
+    }
+
+    let base_asset_amount = if target == 0 {
+        // flatten fully (spot balances accrue interest off the step size)
+        standardize_base_asset_amount_ceil(diff.unsigned_abs().cast()?, step_size)?
+    } else {
+        // round down so dust/interest doesnt trade back and forth every pass
+        standardize_base_asset_amount(diff.unsigned_abs().cast()?, step_size)?
+    };
+    if base_asset_amount == 0 {
+        return Ok(None);
+    }
+
+    let direction = if diff > 0 {
+        PositionDirection::Long
+    } else {
+        PositionDirection::Short
+    };
+
+    // only perp orders which shrink the position (without flipping it) are reduce only --
+    // spot closes are rounded up so they would be rejected as reduce only
+    let is_shrinking = current != 0
+        && (target == 0 || target.signum() == current.signum())
+        && target.unsigned_abs() < current.unsigned_abs();
+    let reduce_only = market_type == MarketType::Perp && is_shrinking;
+
+    Ok(Some(OrderIntent {
+        market_type,
+        market_index,
+        direction,
+        base_asset_amount,
+        reduce_only,
+    }))
+}
+
+// orders needed to move the user's perp + spot legs to the target
+// (handles flips, partial fills and resizing towards the target size)
+pub fn plan_rebalance(
+    user: &User,
+    perp_market: &PerpMarket,
+    spot_market: &SpotMarket,
+    target: &RebalanceTarget,
+) -> DriftResult<Vec<OrderIntent>> {
+    let current_perp = i128::from(
+        user.get_perp_position(perp_market.market_index)
+            .map_or(0, |position| position.base_asset_amount)
+    );
+    let current_spot = match user.get_spot_position(spot_market.market_index) {
+        Some(position) => position.get_signed_token_amount(spot_market)?,
+        None => 0,
+    };
+
+    plan_rebalance_from(current_perp, current_spot, perp_market, spot_market, target)
+}
+
+// same as plan_rebalance from signed perp base (1e9) + spot token amounts
+pub fn plan_rebalance_from(
+    current_perp: i128,
+    current_spot: i128,
+    perp_market: &PerpMarket,
+    spot_market: &SpotMarket,
+    target: &RebalanceTarget,
+) -> DriftResult<Vec<OrderIntent>> {
+    let mut intents = vec![];
+
+    // perp leg
+    let target_perp = if target.close {
+        0
+    } else {
+        let amount = standardize_base_asset_amount_ceil(
+            target.base_asset_amount,
+            perp_market.amm.order_step_size,
+        )?;
+        signed_amount(amount, target.perp_direction)?
+    };
+
+    if let Some(intent) = plan_leg(
+        MarketType::Perp,
+        perp_market.market_index,
+        current_perp,
+        target_perp,
+        perp_market.amm.order_step_size,
+    )? {
+        intents.push(intent);
+    }
+
+    // spot leg
+    let target_spot = if target.close {
+        0
+    } else {
+        let amount = standardize_base_asset_amount_ceil(
+            base_to_token_amount(target.base_asset_amount, spot_market)?,
+            spot_market.order_step_size,
+        )?;
+        let direction = match target.spot_balance_type {
+            SpotBalanceType::Deposit => PositionDirection::Long,
+            SpotBalanceType::Borrow => PositionDirection::Short,
+        };
+        signed_amount(amount, direction)?
+    };
+
+    if let Some(intent) = plan_leg(
+        MarketType::Spot,
+        spot_market.market_index,
+        current_spot,
+        target_spot,
+        spot_market.order_step_size,
+    )? {
+        intents.push(intent);
+    }
+
+    Ok(intents)
+}
+
+// perp base + spot (token -> base) amounts, signed
+pub fn get_current_legs(user: &User, perp_market: &PerpMarket, spot_market: &SpotMarket) -> DriftResult<(i128, i128)> {
+    let current_perp = i128::from(
+        user.get_perp_position(perp_market.market_index)
+            .map_or(0, |position| position.base_asset_amount)
+    );
+    let current_spot = match user.get_spot_position(spot_market.market_index) {
+        Some(position) => token_to_base_amount(position.get_signed_token_amount(spot_market)?, spot_market)?,
+        None => 0,
+    };
+    Ok((current_perp, current_spot))
+}
+
+// perp base + spot base (0 = delta neutral)
+pub fn compute_net_delta(user: &User, perp_market: &PerpMarket, spot_market: &SpotMarket) -> DriftResult<i128> {
+    let (current_perp, current_spot) = get_current_legs(user, perp_market, spot_market)?;
+    current_perp.safe_add(current_spot)
+}
+
+// corrective order on the leg furthest from its target so the legs offset each other again
+// (none when the net delta is within the tolerance or smaller than the leg's step size)
+pub fn plan_reconcile(
+    user: &User,
+    perp_market: &PerpMarket,
+    spot_market: &SpotMarket,
+    target: &RebalanceTarget,
+    tolerance: u64,
+) -> DriftResult<Option<OrderIntent>> {
+    let (current_perp, current_spot) = get_current_legs(user, perp_market, spot_market)?;
+    let net_delta = current_perp.safe_add(current_spot)?;
+    if net_delta.unsigned_abs() <= tolerance.cast()? {
+        return Ok(None);
+    }
+
+    let (target_perp, target_spot) = if target.close {
+        (0, 0)
+    } else {
+        let amount = target.base_asset_amount.cast::<i128>()?;
+        match target.perp_direction {
+            PositionDirection::Long => (amount, -amount),
+            PositionDirection::Short => (-amount, amount),
+        }
+    };
+    let perp_lag = target_perp.safe_sub(current_perp)?.unsigned_abs();
+    let spot_lag = target_spot.safe_sub(current_spot)?.unsigned_abs();
+
+    let direction = if net_delta > 0 {
+        PositionDirection::Short
+    } else {
+        PositionDirection::Long
+    };
+
+    let (market_type, market_index, base_asset_amount, reduce_only) = if perp_lag >= spot_lag {
+        let amount = standardize_base_asset_amount(net_delta.unsigned_abs().cast()?, perp_market.amm.order_step_size)?;
+        // only reduce only if it doesnt flip the perp
+        let reduce_only = current_perp != 0
+            && net_delta.signum() == current_perp.signum()
+            && amount.cast::<u128>()? <= current_perp.unsigned_abs();
+        (MarketType::Perp, perp_market.market_index, amount, reduce_only)
+    } else {
+        let amount = standardize_base_asset_amount(
+            base_to_token_amount(net_delta.unsigned_abs().cast()?, spot_market)?,
+            spot_market.order_step_size,
+        )?;
+        (MarketType::Spot, spot_market.market_index, amount, false)
+    };
+
+    if base_asset_amount == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(OrderIntent {
+        market_type,
+        market_index,
+        direction,
+        base_asset_amount,
+        reduce_only,
+    }))
+}
+
+// orders shrinking whichever leg filled more back down to the other leg
+// (eg. after one leg of an atomic placement only partially filled)
+pub fn plan_unwind(
+    user: &User,
+    perp_market: &PerpMarket,
+    spot_market: &SpotMarket,
+) -> DriftResult<Vec<OrderIntent>> {
+    let (current_perp, current_spot) = get_current_legs(user, perp_market, spot_market)?;
+
+    // the legs only hedge each other when they're on opposite sides
+    let is_hedged = current_perp.signum() == -current_spot.signum();
+    let matched = if is_hedged {
+        current_perp.unsigned_abs().min(current_spot.unsigned_abs())
+    } else {
+        0
+    };
+
+    let (perp_direction, spot_balance_type) = if current_perp >= 0 {
+        (PositionDirection::Long, SpotBalanceType::Borrow)
+    } else {
+        (PositionDirection::Short, SpotBalanceType::Deposit)
+    };
+    let target = RebalanceTarget {
+        base_asset_amount: matched.cast()?,
+        perp_direction,
+        spot_balance_type,
+        close: matched == 0,
+    };
+
+    // the smaller leg already sits at the target (rounding down leaves it untouched)
+    plan_rebalance(user, perp_market, spot_market, &target)
+}
+
+// orders shrinking both legs by the same fraction (PERCENTAGE_PRECISION) of their size
+// (eg. to bring account health back up)
+pub fn plan_delever(
+    user: &User,
+    perp_market: &PerpMarket,
+    spot_market: &SpotMarket,
+    fraction: u128,
+) -> DriftResult<Vec<OrderIntent>> {
+    let keep = PERCENTAGE_PRECISION.saturating_sub(fraction).cast::<i128>()?;
+    let shrink = |current: i128| current.safe_mul(keep)?.safe_div(PERCENTAGE_PRECISION.cast()?);
+
+    let mut intents = vec![];
+
+    let current_perp = i128::from(
+        user.get_perp_position(perp_market.market_index)
+            .map_or(0, |position| position.base_asset_amount)
+    );
+    if let Some(intent) = plan_leg(
+        MarketType::Perp,
+        perp_market.market_index,
+        current_perp,
+        shrink(current_perp)?,
+        perp_market.amm.order_step_size,
+    )? {
+        intents.push(intent);
+    }
+
+    let current_spot = match user.get_spot_position(spot_market.market_index) {
+        Some(position) => position.get_signed_token_amount(spot_market)?,
+        None => 0,
+    };
+    if let Some(intent) = plan_leg(
+        MarketType::Spot,
+        spot_market.market_index,
+        current_spot,
+        shrink(current_spot)?,
+        spot_market.order_step_size,
+    )? {
+        intents.push(intent);
+    }
+
+    Ok(intents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use drift::controller::position::PositionDirection::{Long, Short};
+    use drift::math::constants::BASE_PRECISION_U64;
+    use drift::state::user::MarketType::{Perp, Spot};
+
+    use crate::test_utils;
+
+    const STEP: u64 = BASE_PRECISION_U64 / 100;
+    const ONE: i64 = BASE_PRECISION_U64 as i64;
+    const HALF: i64 = ONE / 2;
+
+    fn perp_market() -> PerpMarket {
+        test_utils::perp_market(0, STEP)
+    }
+
+    fn spot_market() -> SpotMarket {
+        test_utils::spot_market(1, STEP)
+    }
+
+    // signed perp base / spot token amounts (negative spot = borrow)
+    fn user(perp: i64, spot: i64) -> User {
+        test_utils::user(&perp_market(), &spot_market(), perp, spot)
+    }
+
+    fn target(perp_direction: PositionDirection, close: bool) -> RebalanceTarget {
+        let spot_balance_type = match perp_direction {
+            PositionDirection::Long => SpotBalanceType::Borrow,
+            PositionDirection::Short => SpotBalanceType::Deposit,
+        };
+        RebalanceTarget {
+            base_asset_amount: BASE_PRECISION_U64,
+            perp_direction,
+            spot_balance_type,
+            close,
+        }
+    }
+
+    fn order(market_type: MarketType, direction: PositionDirection, amount: i64, reduce_only: bool) -> OrderIntent {
+        let market_index = match market_type {
+            MarketType::Perp => 0,
+            MarketType::Spot => 1,
+        };
+        OrderIntent {
+            market_type,
+            market_index,
+            direction,
+            base_asset_amount: amount as u64,
+            reduce_only,
+        }
+    }
+
+    fn plan(perp: i64, spot: i64, target: RebalanceTarget) -> Vec<OrderIntent> {
+        plan_rebalance(&user(perp, spot), &perp_market(), &spot_market(), &target).unwrap()
+    }
+
+    #[test]
+    fn no_position_opens_target() {
+        assert_eq!(
+            plan(0, 0, target(Long, false)),
+            vec![order(Perp, Long, ONE, false), order(Spot, Short, ONE, false)]
+        );
+        assert_eq!(
+            plan(0, 0, target(Short, false)),
+            vec![order(Perp, Short, ONE, false), order(Spot, Long, ONE, false)]
+        );
+    }
+
+    #[test]
+    fn no_position_close_does_nothing() {
+        assert_eq!(plan(0, 0, target(Long, true)), vec![]);
+        assert_eq!(plan(0, 0, target(Short, true)), vec![]);
+    }
+
+    #[test]
+    fn correct_direction_smaller_size_tops_up() {
+        // eg. a partial fill
+        assert_eq!(
+            plan(HALF, -HALF, target(Long, false)),
+            vec![order(Perp, Long, HALF, false), order(Spot, Short, HALF, false)]
+        );
+        assert_eq!(
+            plan(-HALF, HALF, target(Short, false)),
+            vec![order(Perp, Short, HALF, false), order(Spot, Long, HALF, false)]
+        );
+    }
+
+    #[test]
+    fn correct_direction_target_size_does_nothing() {
+        assert_eq!(plan(ONE, -ONE, target(Long, false)), vec![]);
+        assert_eq!(plan(-ONE, ONE, target(Short, false)), vec![]);
+    }
+
+    #[test]
+    fn correct_direction_larger_size_resizes_down() {
+        assert_eq!(
+            plan(2 * ONE, -2 * ONE, target(Long, false)),
+            vec![order(Perp, Short, ONE, true), order(Spot, Long, ONE, false)]
+        );
+        assert_eq!(
+            plan(-2 * ONE, 2 * ONE, target(Short, false)),
+            vec![order(Perp, Long, ONE, true), order(Spot, Short, ONE, false)]
+        );
+    }
+
+    #[test]
+    fn wrong_direction_flips_through_zero() {
+        for size in [HALF, ONE, 2 * ONE] {
+            assert_eq!(
+                plan(-size, size, target(Long, false)),
+                vec![order(Perp, Long, size + ONE, false), order(Spot, Short, size + ONE, false)]
+            );
+            assert_eq!(
+                plan(size, -size, target(Short, false)),
+                vec![order(Perp, Short, size + ONE, false), order(Spot, Long, size + ONE, false)]
+            );
+        }
+    }
+
+    #[test]
+    fn close_flattens_any_position() {
+        for size in [HALF, ONE, 2 * ONE] {
+            for direction in [Long, Short] {
+                // arb position (long perp + borrow or short perp + deposit)
+                assert_eq!(
+                    plan(size, -size, target(direction, true)),
+                    vec![order(Perp, Short, size, true), order(Spot, Long, size, false)]
+                );
+                assert_eq!(
+                    plan(-size, size, target(direction, true)),
+                    vec![order(Perp, Long, size, true), order(Spot, Short, size, false)]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn legs_are_planned_independently() {
+        // perp filled but spot didnt
+        assert_eq!(plan(ONE, 0, target(Long, false)), vec![order(Spot, Short, ONE, false)]);
+        // spot filled but perp didnt
+        assert_eq!(plan(0, -ONE, target(Long, false)), vec![order(Perp, Long, ONE, false)]);
+    }
+
+    #[test]
+    fn dust_below_step_size_is_ignored() {
+        // borrow interest accrued a bit past the target
+        assert_eq!(plan(ONE, -ONE - 1, target(Long, false)), vec![]);
+        // but closing rounds up to fully repay it
+        assert_eq!(
+            plan(0, -ONE - 1, target(Long, true)),
+            vec![order(Spot, Long, ONE + STEP as i64, false)]
+        );
+    }
+
+    #[test]
+    fn unwind_shrinks_the_larger_leg() {
+        let unwind = |perp, spot| plan_unwind(&user(perp, spot), &perp_market(), &spot_market()).unwrap();
+
+        // both legs filled the same
+        assert_eq!(unwind(ONE, -ONE), vec![]);
+        assert_eq!(unwind(0, 0), vec![]);
+        // perp filled more than spot
+        assert_eq!(unwind(ONE, -HALF), vec![order(Perp, Short, HALF, true)]);
+        assert_eq!(unwind(-ONE, HALF), vec![order(Perp, Long, HALF, true)]);
+        // spot filled more than perp
+        assert_eq!(unwind(HALF, -ONE), vec![order(Spot, Long, HALF, false)]);
+        assert_eq!(unwind(-HALF, ONE), vec![order(Spot, Short, HALF, false)]);
+    }
+
+    #[test]
+    fn unwind_flattens_a_single_leg() {
+        let unwind = |perp, spot| plan_unwind(&user(perp, spot), &perp_market(), &spot_market()).unwrap();
+
+        assert_eq!(unwind(ONE, 0), vec![order(Perp, Short, ONE, true)]);
+        assert_eq!(unwind(0, -ONE), vec![order(Spot, Long, ONE, false)]);
+        // same side isnt a hedge
+        assert_eq!(unwind(ONE, ONE), vec![order(Perp, Short, ONE, true), order(Spot, Short, ONE, false)]);
+    }
+
+    #[test]
+    fn delever_shrinks_both_legs_proportionally() {
+        let delever = |perp, spot, fraction| {
+            plan_delever(&user(perp, spot), &perp_market(), &spot_market(), fraction).unwrap()
+        };
+        let quarter = PERCENTAGE_PRECISION / 4;
+
+        assert_eq!(delever(ONE, -ONE, 0), vec![]);
+        assert_eq!(delever(ONE, -ONE, quarter), vec![order(Perp, Short, ONE / 4, true), order(Spot, Long, ONE / 4, false)]);
+        assert_eq!(delever(-HALF, HALF, PERCENTAGE_PRECISION / 2), vec![order(Perp, Long, HALF / 2, true), order(Spot, Short, HALF / 2, false)]);
+        // full delever flattens
+        assert_eq!(delever(ONE, -ONE, PERCENTAGE_PRECISION), vec![order(Perp, Short, ONE, true), order(Spot, Long, ONE, false)]);
+    }
+
+    #[test]
+    fn reconcile_corrects_the_lagging_leg() {
+        let reconcile = |perp, spot, target| {
+            plan_reconcile(&user(perp, spot), &perp_market(), &spot_market(), &target, 0).unwrap()
+        };
+
+        // neutral
+        assert_eq!(reconcile(ONE, -ONE, target(Long, false)), None);
+        assert_eq!(reconcile(HALF, -HALF, target(Long, false)), None);
+        // spot lagging on an open
+        assert_eq!(reconcile(ONE, -HALF, target(Long, false)), Some(order(Spot, Short, HALF, false)));
+        assert_eq!(reconcile(-ONE, HALF, target(Short, false)), Some(order(Spot, Long, HALF, false)));
+        // perp lagging on an open
+        assert_eq!(reconcile(HALF, -ONE, target(Long, false)), Some(order(Perp, Long, HALF, false)));
+        assert_eq!(reconcile(-HALF, ONE, target(Short, false)), Some(order(Perp, Short, HALF, false)));
+        // spot lagging on a close
+        assert_eq!(reconcile(0, -ONE, target(Long, true)), Some(order(Spot, Long, ONE, false)));
+        // perp lagging on a close
+        assert_eq!(reconcile(ONE, 0, target(Long, true)), Some(order(Perp, Short, ONE, true)));
+    }
+
+    #[test]
+    fn reconcile_respects_tolerance_and_step_size() {
+        let reconcile = |perp, spot, tolerance| {
+            plan_reconcile(&user(perp, spot), &perp_market(), &spot_market(), &target(Long, false), tolerance).unwrap()
+        };
+
+        assert_eq!(reconcile(ONE, -HALF, HALF as u64), None);
+        assert_eq!(reconcile(ONE, -HALF, HALF as u64 - 1), Some(order(Spot, Short, HALF, false)));
+        // below the step size
+        assert_eq!(reconcile(ONE, -ONE + 1, 0), None);
+        assert_eq!(compute_net_delta(&user(ONE, -ONE + 1), &perp_market(), &spot_market()).unwrap(), 1);
+    }
+
+    #[test]
+    fn spot_amount_uses_token_decimals() {
+        let spot_market = SpotMarket {
+            decimals: 6,
+            order_step_size: 1,
+            ..spot_market()
+        };
+        assert_eq!(base_to_token_amount(BASE_PRECISION_U64, &spot_market).unwrap(), 1_000_000);
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use drift::controller::position::PositionDirection;
+
+use crate::engine::ArbAction;
+
+// rates are APR % in 1e9 precision
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PolicyConfig {
+    // min net APR (after fees + slippage) to open, add to or flip the arb
+    pub entry_net_apr: u128,
+    // an open arb is closed once its carry (funding -/+ borrow/deposit, before costs) is at or below this
+    pub exit_apr: i128,
+    // an open arb isnt closed or flipped before this
+    pub min_hold_seconds: i64,
+    // consecutive samples paying the other side before flipping
+    pub flip_confirmations: u32,
+}
+
+// per perp market override, eg. "0:5:1:24:3"
+//   <perp index>:<entry net APR %>:<exit APR %>:<min hold hours>:<flip confirmations>
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MarketPolicy {
+    pub perp_market_index: u16,
+    pub config: PolicyConfig,
+}
+
+impl FromStr for MarketPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split(':').map(|field| field.trim()).collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "expected <perp index>:<entry net apr>:<exit apr>:<min hold hours>:<flip confirmations>, got {}",
+                s
+            ));
+        }
+        let parse_f64 = |field: &str, name: &str| -> Result<f64, String> {
+            field.parse().map_err(|_| format!("invalid {} {}", name, field))
+        };
+
+        Ok(MarketPolicy {
+            perp_market_index: fields[0].parse().map_err(|_| format!("invalid perp market index {}", fields[0]))?,
+            config: PolicyConfig {
+                entry_net_apr: (parse_f64(fields[1], "entry net apr")?.max(0.0) * 1e9) as u128,
+                exit_apr: (parse_f64(fields[2], "exit apr")? * 1e9) as i128,
+                min_hold_seconds: (parse_f64(fields[3], "min hold hours")? * 3600.0) as i64,
+                flip_confirmations: fields[4].parse().map_err(|_| format!("invalid flip confirmations {}", fields[4]))?,
+            },
+        })
+    }
+}
+
+// the default unless the perp market has an override
+pub fn get_market_policy(default: &PolicyConfig, overrides: &HashMap<u16, PolicyConfig>, perp_market_index: u16) -> PolicyConfig {
+    overrides.get(&perp_market_index).copied().unwrap_or(*default)
+}
+
+// side of the arb a position is on (perp long / spot short = long funding), None when flat
+pub fn get_held_direction(perp_base_asset_amount: i128, spot_base_asset_amount: i128) -> Option<PositionDirection> {
+    let perp_direction = match perp_base_asset_amount {
+        0 => None,
+        amount if amount > 0 => Some(PositionDirection::Long),
+        _ => Some(PositionDirection::Short),
+    };
+    // a spot leg left on its own is still on the side it hedged
+    perp_direction.or(match spot_base_asset_amount {
+        0 => None,
+        amount if amount > 0 => Some(PositionDirection::Short),
+        _ => Some(PositionDirection::Long),
+    })
+}
+
+// what the policy has seen of one market
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PolicyState {
+    // side we're on + when we opened it (None = found open, age unknown)
+    held: Option<(PositionDirection, Option<i64>)>,
+    // side funding has moved to + consecutive samples of it
+    pending_flip: Option<(PositionDirection, u32)>,
+}
+
+// one evaluated sample
+#[derive(Debug, Clone, Copy)]
+pub struct PolicyInput {
+    pub ts: i64,
+    // side receiving funding
+    pub direction: PositionDirection,
+    // funding -/+ borrow/deposit for that side (APR % in 1e9 precision)
+    pub gross_apr: i128,
+    // net APR clears the entry threshold
+    pub should_enter: bool,
+    // flatten regardless
+    pub close: bool,
+}
+
+impl PolicyState {
+    pub fn held_direction(&self) -> Option<PositionDirection> {
+        self.held.map(|(direction, _)| direction)
+    }
+
+    // positions on chain win over what we think we opened (eg. orders that didnt fill or a restart)
+    pub fn sync(&mut self, held_direction: Option<PositionDirection>) {
+        self.held = match (held_direction, self.held) {
+            (None, _) => None,
+            (Some(direction), Some((held, opened_ts))) if direction == held => Some((held, opened_ts)),
+            (Some(direction), _) => Some((direction, None)),
+        };
+    }
+
+    // entry on the net APR, exit on the carry -- the gap between the two + the min hold keep
+    // noisy rates from churning fees, flips need the other side confirmed over several samples
+    pub fn decide(&mut self, config: &PolicyConfig, input: &PolicyInput) -> ArbAction {
+        if input.close {
+            *self = PolicyState::default();
+            return ArbAction::Close;
+        }
+
+        let (held_direction, opened_ts) = match self.held {
+            Some(held) => held,
+            None => {
+                self.pending_flip = None;
+                if input.should_enter {
+                    self.held = Some((input.direction, Some(input.ts)));
+                    return ArbAction::Open;
+                }
+                return ArbAction::Hold;
+            }
+        };
+        let held_long_enough = opened_ts.is_none_or(|ts| input.ts - ts >= config.min_hold_seconds);
+
+        // we're paying funding until the flip is confirmed
+        if input.direction != held_direction {
+            let confirmations = match self.pending_flip {
+                Some((direction, n)) if direction == input.direction => n + 1,
+                _ => 1,
+            };
+            self.pending_flip = Some((input.direction, confirmations));
+            if confirmations < config.flip_confirmations || !held_long_enough {
+                return ArbAction::Hold;
+            }
+
+            self.pending_flip = None;
+            if input.should_enter {
+                self.held = Some((input.direction, Some(input.ts)));
+                return ArbAction::Open;
+            }
+            self.held = None;
+            return ArbAction::Close;
+        }
+
+        self.pending_flip = None;
+        if input.gross_apr <= config.exit_apr && held_long_enough {
+            self.held = None;
+            ArbAction::Close
+        } else if input.should_enter {
+            // tops the position back up to the target
+            ArbAction::Open
+        } else {
+            ArbAction::Hold
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use drift::controller::position::PositionDirection::{Long, Short};
+
+    const ONE_PERCENT: i128 = 1_000_000_000;
+    const HOUR: i64 = 3600;
+
+    fn config() -> PolicyConfig {
+        PolicyConfig {
+            entry_net_apr: 5 * ONE_PERCENT as u128,
+            exit_apr: ONE_PERCENT,
+            min_hold_seconds: HOUR,
+            flip_confirmations: 3,
+        }
+    }
+
+    // should_enter stands in for the net APR clearing entry_net_apr
+    fn input(ts: i64, direction: PositionDirection, gross_apr: i128) -> PolicyInput {
+        PolicyInput {
+            ts,
+            direction,
+            gross_apr,
+            should_enter: gross_apr >= 5 * ONE_PERCENT,
+            close: false,
+        }
+    }
+
+    fn opened(direction: PositionDirection) -> PolicyState {
+        let mut state = PolicyState::default();
+        assert_eq!(state.decide(&config(), &input(0, direction, 6 * ONE_PERCENT)), ArbAction::Open);
+        state
+    }
+
+    #[test]
+    fn no_open_between_exit_and_entry() {
+        let mut state = PolicyState::default();
+        assert_eq!(state.decide(&config(), &input(0, Long, 3 * ONE_PERCENT)), ArbAction::Hold);
+        assert_eq!(state.held_direction(), None);
+        assert_eq!(state.decide(&config(), &input(HOUR, Long, 6 * ONE_PERCENT)), ArbAction::Open);
+        assert_eq!(state.held_direction(), Some(Long));
+    }
+
+    #[test]
+    fn open_arb_is_held_until_carry_reaches_exit() {
+        let mut state = opened(Long);
+        assert_eq!(state.decide(&config(), &input(2 * HOUR, Long, 3 * ONE_PERCENT)), ArbAction::Hold);
+        assert_eq!(state.decide(&config(), &input(3 * HOUR, Long, 2 * ONE_PERCENT)), ArbAction::Hold);
+        assert_eq!(state.decide(&config(), &input(4 * HOUR, Long, ONE_PERCENT)), ArbAction::Close);
+        assert_eq!(state.held_direction(), None);
+        // back in the gap while flat
+        assert_eq!(state.decide(&config(), &input(5 * HOUR, Long, 3 * ONE_PERCENT)), ArbAction::Hold);
+    }
+
+    #[test]
+    fn min_hold_blocks_close() {
+        let mut state = opened(Long);
+        assert_eq!(state.decide(&config(), &input(HOUR / 2, Long, 0)), ArbAction::Hold);
+        assert_eq!(state.held_direction(), Some(Long));
+        assert_eq!(state.decide(&config(), &input(HOUR, Long, 0)), ArbAction::Close);
+    }
+
+    #[test]
+    fn found_open_position_has_no_min_hold() {
+        let mut state = PolicyState::default();
+        state.sync(Some(Short));
+        assert_eq!(state.decide(&config(), &input(0, Short, 0)), ArbAction::Close);
+    }
+
+    #[test]
+    fn flip_needs_confirmations() {
+        let mut state = opened(Long);
+        assert_eq!(state.decide(&config(), &input(HOUR, Short, 6 * ONE_PERCENT)), ArbAction::Hold);
+        assert_eq!(state.decide(&config(), &input(2 * HOUR, Short, 6 * ONE_PERCENT)), ArbAction::Hold);
+        assert_eq!(state.held_direction(), Some(Long));
+        assert_eq!(state.decide(&config(), &input(3 * HOUR, Short, 6 * ONE_PERCENT)), ArbAction::Open);
+        assert_eq!(state.held_direction(), Some(Short));
+    }
+
+    #[test]
+    fn non_confirming_sample_resets_the_flip() {
+        let mut state = opened(Long);
+        assert_eq!(state.decide(&config(), &input(HOUR, Short, 6 * ONE_PERCENT)), ArbAction::Hold);
+        assert_eq!(state.decide(&config(), &input(2 * HOUR, Short, 6 * ONE_PERCENT)), ArbAction::Hold);
+        // funding back on our side
+        assert_eq!(state.decide(&config(), &input(3 * HOUR, Long, 3 * ONE_PERCENT)), ArbAction::Hold);
+        assert_eq!(state.decide(&config(), &input(4 * HOUR, Short, 6 * ONE_PERCENT)), ArbAction::Hold);
+        assert_eq!(state.decide(&config(), &input(5 * HOUR, Short, 6 * ONE_PERCENT)), ArbAction::Hold);
+        assert_eq!(state.held_direction(), Some(Long));
+        assert_eq!(state.decide(&config(), &input(6 * HOUR, Short, 6 * ONE_PERCENT)), ArbAction::Open);
+        assert_eq!(state.held_direction(), Some(Short));
+    }
+
+    #[test]
+    fn confirmed_flip_not_worth_entering_closes() {
+        let mut state = opened(Long);
+        for ts in [HOUR, 2 * HOUR] {
+            assert_eq!(state.decide(&config(), &input(ts, Short, 3 * ONE_PERCENT)), ArbAction::Hold);
+        }
+        assert_eq!(state.decide(&config(), &input(3 * HOUR, Short, 3 * ONE_PERCENT)), ArbAction::Close);
+        assert_eq!(state.held_direction(), None);
+    }
+
+    #[test]
+    fn close_overrides_everything() {
+        // within the min hold + worth entering
+        let mut state = opened(Long);
+        let close = PolicyInput {
+            close: true,
+            ..input(1, Long, 6 * ONE_PERCENT)
+        };
+        assert_eq!(state.decide(&config(), &close), ArbAction::Close);
+        assert_eq!(state.held_direction(), None);
+
+        // mid flip
+        let mut state = opened(Long);
+        assert_eq!(state.decide(&config(), &input(HOUR, Short, 6 * ONE_PERCENT)), ArbAction::Hold);
+        let close = PolicyInput {
+            close: true,
+            ..input(2 * HOUR, Short, 6 * ONE_PERCENT)
+        };
+        assert_eq!(state.decide(&config(), &close), ArbAction::Close);
+        assert_eq!(state.decide(&config(), &input(3 * HOUR, Short, 6 * ONE_PERCENT)), ArbAction::Open);
+    }
+}
@@ -0,0 +1,221 @@
+use drift::controller::position::PositionDirection;
+use drift::error::DriftResult;
+use drift::math::amm::calculate_price;
+use drift::math::casting::Cast;
+use drift::math::constants::PERCENTAGE_PRECISION;
+use drift::math::safe_math::SafeMath;
+use drift::state::perp_market::{PerpMarket, AMM};
+
+#[derive(Debug, Clone, Copy)]
+pub struct AuctionConfig {
+    // slots the auction runs for before the order fills at the end price
+    pub auction_duration: u8,
+    // max distance of the end price from the oracle (PERCENTAGE_PRECISION)
+    pub max_slippage: u128,
+}
+
+// PRICE_PRECISION
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuctionPrices {
+    pub start_price: i64,
+    pub end_price: i64,
+    pub duration: u8,
+}
+
+// oracle +/- max slippage (worse side for the direction)
+fn get_slippage_limit(oracle_price: i64, direction: PositionDirection, max_slippage: u128) -> DriftResult<i64> {
+    let offset = oracle_price
+        .unsigned_abs()
+        .cast::<u128>()?
+        .safe_mul(max_slippage)?
+        .safe_div(PERCENTAGE_PRECISION)?
+        .cast::<i64>()?;
+
+    match direction {
+        PositionDirection::Long => oracle_price.safe_add(offset),
+        PositionDirection::Short => oracle_price.safe_sub(offset),
+    }
+}
+
+// base asset reserve after filling base_asset_amount against the amm + the side's spread
+// (BID_ASK_SPREAD_PRECISION == PERCENTAGE_PRECISION) -- None when the pool doesnt hold that much base
+pub fn calculate_amm_fill(amm: &AMM, base_asset_amount: u64, direction: PositionDirection) -> DriftResult<Option<(u128, u128)>> {
+    let size = base_asset_amount.cast::<u128>()?;
+    match direction {
+        // buying base out of the pool
+        PositionDirection::Long => {
+            if size >= amm.base_asset_reserve {
+                return Ok(None);
+            }
+            Ok(Some((amm.base_asset_reserve.safe_sub(size)?, amm.long_spread.cast()?)))
+        }
+        PositionDirection::Short => Ok(Some((amm.base_asset_reserve.safe_add(size)?, amm.short_spread.cast()?))),
+    }
+}
+
+// reserve price after filling base_asset_amount against the amm + the side's spread (PRICE_PRECISION)
+pub fn calculate_swap_impact_price(
+    perp_market: &PerpMarket,
+    base_asset_amount: u64,
+    direction: PositionDirection,
+) -> DriftResult<i64> {
+    let amm = &perp_market.amm;
+    let k = amm.base_asset_reserve.safe_mul(amm.quote_asset_reserve)?;
+
+    // no price fills it -- the auction clamps it to the max slippage
+    let (new_base_asset_reserve, spread) = match calculate_amm_fill(amm, base_asset_amount, direction)? {
+        Some(fill) => fill,
+        None => return Ok(i64::MAX),
+    };
+    let new_quote_asset_reserve = k.safe_div(new_base_asset_reserve)?;
+
+    let impact_price = calculate_price(new_quote_asset_reserve, new_base_asset_reserve, amm.peg_multiplier)?
+        .cast::<u128>()?;
+    let spread_offset = impact_price
+        .safe_mul(spread)?
+        .safe_div(PERCENTAGE_PRECISION)?;
+
+    match direction {
+        PositionDirection::Long => impact_price.safe_add(spread_offset)?.cast(),
+        PositionDirection::Short => impact_price.safe_sub(spread_offset)?.cast(),
+    }
+}
+
+// auction starts at the oracle and ends at the swap impact price, bounded by the max slippage
+pub fn get_perp_auction_prices(
+    perp_market: &PerpMarket,
+    oracle_price: i64,
+    base_asset_amount: u64,
+    direction: PositionDirection,
+    config: &AuctionConfig,
+) -> DriftResult<AuctionPrices> {
+    let limit_price = get_slippage_limit(oracle_price, direction, config.max_slippage)?;
+    let impact_price = calculate_swap_impact_price(perp_market, base_asset_amount, direction)?;
+
+    // end is never better than the start (the amm can be priced through the oracle)
+    let end_price = match direction {
+        PositionDirection::Long => impact_price.min(limit_price).max(oracle_price),
+        PositionDirection::Short => impact_price.max(limit_price).min(oracle_price),
+    };
+
+    Ok(AuctionPrices {
+        start_price: oracle_price,
+        end_price,
+        duration: config.auction_duration,
+    })
+}
+
+// spot doesnt fill against an amm so the auction runs from the oracle to the max slippage
+pub fn get_spot_auction_prices(
+    oracle_price: i64,
+    direction: PositionDirection,
+    config: &AuctionConfig,
+) -> DriftResult<AuctionPrices> {
+    Ok(AuctionPrices {
+        start_price: oracle_price,
+        end_price: get_slippage_limit(oracle_price, direction, config.max_slippage)?,
+        duration: config.auction_duration,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use drift::controller::position::PositionDirection::{Long, Short};
+    use drift::math::constants::{AMM_RESERVE_PRECISION, BASE_PRECISION_U64, PEG_PRECISION, PRICE_PRECISION_I64};
+
+    const PRICE: i64 = 100 * PRICE_PRECISION_I64;
+    const SIZE: u64 = 10 * BASE_PRECISION_U64;
+
+    // reserve price 100, 10 base moves it ~2%
+    fn perp_market() -> PerpMarket {
+        PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 1_000 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 1_000 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                ..AMM::default()
+            },
+            ..PerpMarket::default()
+        }
+    }
+
+    // PERCENTAGE_PRECISION
+    fn config(max_slippage: u128) -> AuctionConfig {
+        AuctionConfig {
+            auction_duration: 10,
+            max_slippage,
+        }
+    }
+
+    fn perp_auction(oracle_price: i64, base_asset_amount: u64, direction: PositionDirection, max_slippage: u128) -> AuctionPrices {
+        get_perp_auction_prices(&perp_market(), oracle_price, base_asset_amount, direction, &config(max_slippage)).unwrap()
+    }
+
+    #[test]
+    fn swap_impact_moves_against_the_taker() {
+        let long = calculate_swap_impact_price(&perp_market(), SIZE, Long).unwrap();
+        let short = calculate_swap_impact_price(&perp_market(), SIZE, Short).unwrap();
+        // (1000 / 990)^2 and (1000 / 1010)^2
+        assert_eq!(long, 102_030_405);
+        assert_eq!(short, 98_029_604);
+
+        let mut wide = perp_market();
+        wide.amm.long_spread = 1_000;
+        wide.amm.short_spread = 1_000;
+        assert!(calculate_swap_impact_price(&wide, SIZE, Long).unwrap() > long);
+        assert!(calculate_swap_impact_price(&wide, SIZE, Short).unwrap() < short);
+    }
+
+    #[test]
+    fn unfillable_swap_has_no_price() {
+        assert_eq!(calculate_swap_impact_price(&perp_market(), 1_000 * BASE_PRECISION_U64, Long).unwrap(), i64::MAX);
+    }
+
+    #[test]
+    fn perp_auction_runs_from_oracle_to_impact() {
+        let long = perp_auction(PRICE, SIZE, Long, 50_000);
+        assert_eq!(long, AuctionPrices { start_price: PRICE, end_price: 102_030_405, duration: 10 });
+
+        let short = perp_auction(PRICE, SIZE, Short, 50_000);
+        assert_eq!(short, AuctionPrices { start_price: PRICE, end_price: 98_029_604, duration: 10 });
+    }
+
+    #[test]
+    fn perp_auction_end_is_clamped_to_max_slippage() {
+        assert_eq!(perp_auction(PRICE, SIZE, Long, 10_000).end_price, 101 * PRICE_PRECISION_I64);
+        assert_eq!(perp_auction(PRICE, SIZE, Short, 10_000).end_price, 99 * PRICE_PRECISION_I64);
+
+        // more base than the pool holds
+        assert_eq!(perp_auction(PRICE, 1_000 * BASE_PRECISION_U64, Long, 10_000).end_price, 101 * PRICE_PRECISION_I64);
+    }
+
+    #[test]
+    fn perp_auction_end_is_never_better_than_the_start() {
+        // amm priced through the oracle
+        let long = perp_auction(110 * PRICE_PRECISION_I64, SIZE, Long, 50_000);
+        assert_eq!(long.start_price, long.end_price);
+        let short = perp_auction(90 * PRICE_PRECISION_I64, SIZE, Short, 50_000);
+        assert_eq!(short.start_price, short.end_price);
+    }
+
+    #[test]
+    fn spot_auction_runs_from_oracle_to_max_slippage() {
+        let long = get_spot_auction_prices(PRICE, Long, &config(20_000)).unwrap();
+        assert_eq!(long, AuctionPrices { start_price: PRICE, end_price: 102 * PRICE_PRECISION_I64, duration: 10 });
+
+        let short = get_spot_auction_prices(PRICE, Short, &config(20_000)).unwrap();
+        assert_eq!(short, AuctionPrices { start_price: PRICE, end_price: 98 * PRICE_PRECISION_I64, duration: 10 });
+    }
+
+    #[test]
+    fn amm_fill_is_none_past_the_pool() {
+        let amm = perp_market().amm;
+        assert_eq!(calculate_amm_fill(&amm, SIZE, Long).unwrap(), Some((990 * AMM_RESERVE_PRECISION, 0)));
+        assert_eq!(calculate_amm_fill(&amm, SIZE, Short).unwrap(), Some((1_010 * AMM_RESERVE_PRECISION, 0)));
+        assert_eq!(calculate_amm_fill(&amm, 1_000 * BASE_PRECISION_U64, Long).unwrap(), None);
+        // selling into the pool always fills
+        assert!(calculate_amm_fill(&amm, 1_000 * BASE_PRECISION_U64, Short).unwrap().is_some());
+    }
+}
@@ -0,0 +1,245 @@
+use drift::controller::position::PositionDirection;
+use drift::error::DriftResult;
+use drift::math::casting::Cast;
+use drift::math::constants::{ONE_YEAR, PERCENTAGE_PRECISION};
+use drift::math::safe_math::SafeMath;
+use drift::state::perp_market::PerpMarket;
+use drift::state::state::{FeeStructure, State};
+
+use crate::pricing::calculate_amm_fill;
+
+// PERCENTAGE_PRECISION (1e6) fraction -> APR % in 1e9 precision (same units as compute_funding_rate)
+const PERCENTAGE_TO_APR: u128 = 100_000;
+// orders the amm cant fill are costed at 100% so they're never worth entering
+const MAX_SLIPPAGE: u128 = PERCENTAGE_PRECISION;
+
+// costs of trading one leg as a fraction of notional (PERCENTAGE_PRECISION)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TradeCosts {
+    pub perp_fee: u128,
+    pub spot_fee: u128,
+    pub perp_slippage: u128,
+    pub spot_slippage: u128,
+}
+
+impl TradeCosts {
+    // entering (or exiting) both legs once
+    pub fn total(&self) -> DriftResult<u128> {
+        self.perp_fee
+            .safe_add(self.spot_fee)?
+            .safe_add(self.perp_slippage)?
+            .safe_add(self.spot_slippage)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProfitabilityConfig {
+    // entry + exit costs are amortized over how long we expect to hold the arb
+    pub holding_horizon_seconds: i64,
+    // spot doesnt fill against the amm so its slippage is a flat estimate (PERCENTAGE_PRECISION)
+    pub spot_slippage: u128,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Profitability {
+    // funding - borrow (APR % in 1e9 precision)
+    pub gross_apr: u128,
+    pub costs: TradeCosts,
+    pub holding_horizon_seconds: i64,
+    // round trip costs spread over the holding horizon (APR % in 1e9 precision)
+    pub amortized_cost_apr: u128,
+    pub net_apr: i128,
+    pub min_net_apr: u128,
+    pub should_enter: bool,
+}
+
+// taker fee of the lowest tier (PERCENTAGE_PRECISION)
+pub fn get_taker_fee(fee_structure: &FeeStructure) -> DriftResult<u128> {
+    let tier = fee_structure.fee_tiers[0];
+    if tier.fee_denominator == 0 {
+        return Ok(0);
+    }
+
+    tier.fee_numerator
+        .cast::<u128>()?
+        .safe_mul(PERCENTAGE_PRECISION)?
+        .safe_div(tier.fee_denominator.cast()?)
+}
+
+// spread + constant product price impact of filling against the amm (PERCENTAGE_PRECISION)
+//   long: avg price / reserve price = b / (b - size)
+//   short: avg price / reserve price = b / (b + size)
+pub fn estimate_amm_slippage(
+    perp_market: &PerpMarket,
+    base_asset_amount: u64,
+    direction: PositionDirection,
+) -> DriftResult<u128> {
+    let (new_base_asset_reserve, spread) = match calculate_amm_fill(&perp_market.amm, base_asset_amount, direction)? {
+        Some(fill) => fill,
+        None => return Ok(MAX_SLIPPAGE),
+    };
+
+    let price_impact = base_asset_amount
+        .cast::<u128>()?
+        .safe_mul(PERCENTAGE_PRECISION)?
+        .safe_div(new_base_asset_reserve)?;
+
+    spread.safe_add(price_impact)
+}
+
+pub fn estimate_trade_costs(
+    state: &State,
+    perp_market: &PerpMarket,
+    base_asset_amount: u64,
+    direction: PositionDirection,
+    config: &ProfitabilityConfig,
+) -> DriftResult<TradeCosts> {
+    Ok(TradeCosts {
+        perp_fee: get_taker_fee(&state.perp_fee_structure)?,
+        spot_fee: get_taker_fee(&state.spot_fee_structure)?,
+        perp_slippage: estimate_amm_slippage(perp_market, base_asset_amount, direction)?,
+        spot_slippage: config.spot_slippage,
+    })
+}
+
+pub fn compute_profitability(
+    gross_apr: u128,
+    costs: TradeCosts,
+    config: &ProfitabilityConfig,
+    // the policy's entry threshold (APR % in 1e9 precision)
+    min_net_apr: u128,
+) -> DriftResult<Profitability> {
+    let holding_horizon_seconds = config.holding_horizon_seconds.max(1);
+
+    // enter + exit both legs
+    let round_trip_cost = costs.total()?.safe_mul(2)?;
+    let amortized_cost_apr = round_trip_cost
+        .safe_mul(ONE_YEAR)?
+        .safe_div(holding_horizon_seconds.cast()?)?
+        .safe_mul(PERCENTAGE_TO_APR)?;
+
+    let net_apr = gross_apr
+        .cast::<i128>()?
+        .safe_sub(amortized_cost_apr.cast()?)?;
+    let should_enter = gross_apr > 0 && net_apr >= min_net_apr.cast()?;
+
+    Ok(Profitability {
+        gross_apr,
+        costs,
+        holding_horizon_seconds,
+        amortized_cost_apr,
+        net_apr,
+        min_net_apr,
+        should_enter,
+    })
+}
+
+pub fn print_profitability(profitability: &Profitability) {
+    let Profitability { gross_apr, costs, holding_horizon_seconds, amortized_cost_apr, net_apr, min_net_apr, should_enter } = *profitability;
+    let pct = |x: u128| x as f64 / PERCENTAGE_PRECISION as f64 * 100.0;
+
+    println!("PROFIT: gross APR % {}", gross_apr as f64 / 1e9);
+    println!(
+        "PROFIT: entry costs % -- perp fee {} spot fee {} perp slippage {} spot slippage {}",
+        pct(costs.perp_fee), pct(costs.spot_fee), pct(costs.perp_slippage), pct(costs.spot_slippage),
+    );
+    println!(
+        "PROFIT: entry + exit cost APR % {} (over {} days)",
+        amortized_cost_apr as f64 / 1e9,
+        holding_horizon_seconds as f64 / 86_400.0,
+    );
+    println!(
+        "PROFIT: net APR % {} (min {}) -> {}",
+        net_apr as f64 / 1e9,
+        min_net_apr as f64 / 1e9,
+        if should_enter { "GO" } else { "NO GO" },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use drift::math::constants::BASE_PRECISION;
+    use drift::state::perp_market::AMM;
+
+    const ONE_PERCENT_APR: u128 = 1_000_000_000;
+
+    fn perp_market() -> PerpMarket {
+        PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 1_000 * BASE_PRECISION,
+                ..AMM::default()
+            },
+            ..PerpMarket::default()
+        }
+    }
+
+    fn config(holding_horizon_seconds: i64) -> ProfitabilityConfig {
+        ProfitabilityConfig {
+            holding_horizon_seconds,
+            spot_slippage: 0,
+        }
+    }
+
+    #[test]
+    fn amm_slippage_is_the_constant_product_impact() {
+        let size = (10 * BASE_PRECISION) as u64;
+        // 10 / 990 and 10 / 1010
+        assert_eq!(estimate_amm_slippage(&perp_market(), size, PositionDirection::Long).unwrap(), 10_101);
+        assert_eq!(estimate_amm_slippage(&perp_market(), size, PositionDirection::Short).unwrap(), 9_900);
+    }
+
+    #[test]
+    fn unfillable_amm_order_is_never_worth_entering() {
+        let size = (1_000 * BASE_PRECISION) as u64;
+        let slippage = estimate_amm_slippage(&perp_market(), size, PositionDirection::Long).unwrap();
+        assert_eq!(slippage, MAX_SLIPPAGE);
+
+        let costs = TradeCosts {
+            perp_slippage: slippage,
+            ..TradeCosts::default()
+        };
+        let profitability = compute_profitability(100 * ONE_PERCENT_APR, costs, &config(ONE_YEAR as i64), 0).unwrap();
+        assert!(!profitability.should_enter);
+    }
+
+    #[test]
+    fn round_trip_costs_are_amortized_over_the_horizon() {
+        // 0.1% fee per leg -> 0.4% round trip
+        let costs = TradeCosts {
+            perp_fee: PERCENTAGE_PRECISION / 1_000,
+            spot_fee: PERCENTAGE_PRECISION / 1_000,
+            ..TradeCosts::default()
+        };
+        let gross_apr = 10 * ONE_PERCENT_APR;
+
+        let yearly = compute_profitability(gross_apr, costs, &config(ONE_YEAR as i64), 0).unwrap();
+        assert_eq!(yearly.amortized_cost_apr, 4 * ONE_PERCENT_APR / 10);
+        assert_eq!(yearly.net_apr, (96 * ONE_PERCENT_APR / 10) as i128);
+
+        // same costs over half the time cost twice the APR
+        let half_year = compute_profitability(gross_apr, costs, &config(ONE_YEAR as i64 / 2), 0).unwrap();
+        assert_eq!(half_year.amortized_cost_apr, 8 * ONE_PERCENT_APR / 10);
+        assert_eq!(half_year.net_apr, (92 * ONE_PERCENT_APR / 10) as i128);
+    }
+
+    #[test]
+    fn enters_only_at_or_above_min_net_apr() {
+        let costs = TradeCosts {
+            perp_fee: PERCENTAGE_PRECISION / 1_000,
+            spot_fee: PERCENTAGE_PRECISION / 1_000,
+            ..TradeCosts::default()
+        };
+        let should_enter = |gross_apr, min_net_apr| {
+            compute_profitability(gross_apr, costs, &config(ONE_YEAR as i64), min_net_apr)
+                .unwrap()
+                .should_enter
+        };
+
+        assert!(should_enter(10 * ONE_PERCENT_APR, 96 * ONE_PERCENT_APR / 10));
+        assert!(!should_enter(10 * ONE_PERCENT_APR, 97 * ONE_PERCENT_APR / 10));
+        // no carry is never entered, even with no costs
+        assert!(!compute_profitability(0, TradeCosts::default(), &config(ONE_YEAR as i64), 0).unwrap().should_enter);
+    }
+}
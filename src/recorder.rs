@@ -0,0 +1,252 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use drift::controller::position::PositionDirection;
+
+use crate::constants::*;
+
+pub const CSV_HEADER: &str = "ts,slot,perp_market_index,spot_market_index,symbol,\
+    funding_apr,funding_direction,last_funding_apr,last_24h_avg_funding_apr,\
+    borrow_apr,deposit_apr,utilization,oracle_price,oracle_twap,mark_twap,net_apr,action";
+
+// one evaluated sample of a perp/spot pair
+//   rates = APR % in 1e9 precision, utilization = SPOT_UTILIZATION_PRECISION, prices = PRICE_PRECISION
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateSample {
+    pub ts: i64,
+    pub slot: u64,
+    pub perp_market_index: u16,
+    pub spot_market_index: u16,
+    pub symbol: String,
+    // predicted (accruing) rate + the side receiving it
+    pub funding_apr: u128,
+    pub funding_direction: PositionDirection,
+    pub last_funding_apr: i128,
+    pub last_24h_avg_funding_apr: i128,
+    pub borrow_apr: u128,
+    pub deposit_apr: u128,
+    pub utilization: u128,
+    pub oracle_price: i64,
+    pub oracle_twap: i64,
+    pub mark_twap: u64,
+    // after fees + slippage
+    pub net_apr: i128,
+    pub action: String,
+}
+
+impl RateSample {
+    pub fn to_csv_row(&self) -> String {
+        let funding_direction = match self.funding_direction {
+            PositionDirection::Long => "long",
+            PositionDirection::Short => "short",
+        };
+        format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            self.ts,
+            self.slot,
+            self.perp_market_index,
+            self.spot_market_index,
+            self.symbol,
+            self.funding_apr,
+            funding_direction,
+            self.last_funding_apr,
+            self.last_24h_avg_funding_apr,
+            self.borrow_apr,
+            self.deposit_apr,
+            self.utilization,
+            self.oracle_price,
+            self.oracle_twap,
+            self.mark_twap,
+            self.net_apr,
+            self.action,
+        )
+    }
+}
+
+fn get_field<'a>(header: &[&str], fields: &[&'a str], name: &str, line: usize) -> Result<&'a str> {
+    header
+        .iter()
+        .position(|column| column.trim() == name)
+        .and_then(|index| fields.get(index).copied())
+        .ok_or_else(|| DriftError::InvalidRecord(line, format!("missing {}", name)))
+}
+
+fn parse_field<T: FromStr>(field: &str, name: &str, line: usize) -> Result<T> {
+    field
+        .trim()
+        .parse()
+        .map_err(|_| DriftError::InvalidRecord(line, format!("invalid {} {}", name, field)))
+}
+
+impl RateSample {
+    // columns are looked up by the header so older files still parse
+    pub fn from_csv_row(header: &[&str], row: &str, line: usize) -> Result<Self> {
+        let fields: Vec<&str> = row.split(',').collect();
+        let get = |name: &str| get_field(header, &fields, name, line);
+        let parse_u128 = |name: &str| parse_field::<u128>(get(name)?, name, line);
+        let parse_i128 = |name: &str| parse_field::<i128>(get(name)?, name, line);
+
+        let funding_direction = match get("funding_direction")?.trim() {
+            "long" => PositionDirection::Long,
+            "short" => PositionDirection::Short,
+            other => return Err(DriftError::InvalidRecord(line, format!("invalid funding_direction {}", other))),
+        };
+
+        Ok(RateSample {
+            ts: parse_field(get("ts")?, "ts", line)?,
+            slot: parse_field(get("slot")?, "slot", line)?,
+            perp_market_index: parse_field(get("perp_market_index")?, "perp_market_index", line)?,
+            spot_market_index: parse_field(get("spot_market_index")?, "spot_market_index", line)?,
+            symbol: get("symbol")?.trim().to_string(),
+            funding_apr: parse_u128("funding_apr")?,
+            funding_direction,
+            last_funding_apr: parse_i128("last_funding_apr")?,
+            last_24h_avg_funding_apr: parse_i128("last_24h_avg_funding_apr")?,
+            borrow_apr: parse_u128("borrow_apr")?,
+            deposit_apr: parse_u128("deposit_apr")?,
+            utilization: parse_u128("utilization")?,
+            oracle_price: parse_field(get("oracle_price")?, "oracle_price", line)?,
+            oracle_twap: parse_field(get("oracle_twap")?, "oracle_twap", line)?,
+            mark_twap: parse_field(get("mark_twap")?, "mark_twap", line)?,
+            net_apr: parse_i128("net_apr")?,
+            action: get("action")?.trim().to_string(),
+        })
+    }
+}
+
+// every sample recorded to a csv (oldest first)
+pub fn read_samples(path: impl AsRef<Path>) -> Result<Vec<RateSample>> {
+    let contents = fs::read_to_string(path)?;
+    let mut lines = contents.lines();
+
+    let header: Vec<&str> = match lines.next() {
+        Some(header) => header.split(',').collect(),
+        None => return Ok(vec![]),
+    };
+
+    let mut samples = vec![];
+    for (i, row) in lines.enumerate() {
+        if row.trim().is_empty() {
+            continue;
+        }
+        // + 2 = 1-indexed + header
+        samples.push(RateSample::from_csv_row(&header, row, i + 2)?);
+    }
+
+    samples.sort_by_key(|sample| sample.ts);
+    Ok(samples)
+}
+
+// append-only csv of every evaluated sample (header written when the file is new)
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    path: PathBuf,
+}
+
+impl Recorder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Recorder { path: path.into() }
+    }
+
+    pub fn record(&self, sample: &RateSample) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "{}", CSV_HEADER)?;
+        }
+        writeln!(file, "{}", sample.to_csv_row())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(ts: i64, funding_direction: PositionDirection) -> RateSample {
+        RateSample {
+            ts,
+            slot: 170_000_000,
+            perp_market_index: 0,
+            spot_market_index: 1,
+            symbol: "SOL".to_string(),
+            funding_apr: 12_500_000_000,
+            funding_direction,
+            last_funding_apr: -3_000_000_000,
+            last_24h_avg_funding_apr: 8_000_000_000,
+            borrow_apr: 4_000_000_000,
+            deposit_apr: 1_000_000_000,
+            utilization: 450_000,
+            oracle_price: 13_420_000,
+            oracle_twap: -1,
+            mark_twap: 13_410_000,
+            net_apr: -250_000_000,
+            action: "Hold".to_string(),
+        }
+    }
+
+    fn header() -> Vec<&'static str> {
+        CSV_HEADER.split(',').collect()
+    }
+
+    #[test]
+    fn csv_row_round_trips() {
+        for direction in [PositionDirection::Long, PositionDirection::Short] {
+            let sample = sample(1_670_000_000, direction);
+            assert_eq!(RateSample::from_csv_row(&header(), &sample.to_csv_row(), 2).unwrap(), sample);
+        }
+    }
+
+    #[test]
+    fn columns_are_read_by_header() {
+        let sample = sample(1_670_000_000, PositionDirection::Long);
+        let mut header = header();
+        let mut fields: Vec<String> = sample.to_csv_row().split(',').map(String::from).collect();
+        header.reverse();
+        fields.reverse();
+        assert_eq!(RateSample::from_csv_row(&header, &fields.join(","), 2).unwrap(), sample);
+    }
+
+    #[test]
+    fn invalid_rows_name_the_line() {
+        let row = sample(1_670_000_000, PositionDirection::Long).to_csv_row();
+
+        let missing_column: Vec<&str> = header().into_iter().filter(|column| *column != "net_apr").collect();
+        assert!(matches!(
+            RateSample::from_csv_row(&missing_column, &row, 3),
+            Err(DriftError::InvalidRecord(3, _))
+        ));
+        assert!(matches!(
+            RateSample::from_csv_row(&header(), &row.replace(",long,", ",sideways,"), 4),
+            Err(DriftError::InvalidRecord(4, _))
+        ));
+        assert!(matches!(
+            RateSample::from_csv_row(&header(), &row.replacen("1670000000", "yesterday", 1), 5),
+            Err(DriftError::InvalidRecord(5, _))
+        ));
+    }
+
+    #[test]
+    fn recorded_samples_are_read_back_oldest_first() {
+        let path = std::env::temp_dir().join(format!("drift-funding-arb-recorder-{}.csv", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let recorder = Recorder::new(&path);
+        let samples = [
+            sample(1_670_000_060, PositionDirection::Short),
+            sample(1_670_000_000, PositionDirection::Long),
+        ];
+        for sample in samples.iter() {
+            recorder.record(sample).unwrap();
+        }
+
+        let read = read_samples(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read, vec![samples[1].clone(), samples[0].clone()]);
+    }
+}
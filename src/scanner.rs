@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::ops::Mul;
+
+use anchor_client::solana_client::rpc_client::RpcClient;
+use anchor_client::solana_sdk::pubkey::Pubkey;
+
+use drift::controller::position::PositionDirection;
+use drift::state::state::State;
+
+use crate::address::*;
+use crate::constants::*;
+use crate::math::{compute_funding_rate, compute_borrow_rate, compute_deposit_rate, gross_apr};
+use crate::utils::*;
+
+// rates are APR % in 1e9 precision
+#[derive(Debug, Clone)]
+pub struct PairCarry { 
+    pub symbol: String,
+    pub perp_market_index: u16,
+    pub spot_market_index: u16,
+    pub funding_apr: u128,
+    pub funding_direction: PositionDirection,
+    pub borrow_apr: u128,
+    pub deposit_apr: u128,
+    // funding - borrow (longs paid) or funding + deposit (shorts paid)
+    pub net_carry: i128,
+}
+
+// pairs every perp with the spot market(s) of the same symbol, ranked by net carry -- markets
+// whose rates cant be computed (eg. a dead oracle) are skipped instead of failing the scan
+pub fn scan_markets(
+    connection: &RpcClient, 
+    state_account: &State, 
+    cached_accounts: &HashMap<Pubkey, Market>,
+    program_id: &Pubkey,
+) -> Result<Vec<PairCarry>> { 
+    // several spot markets can share a symbol (eg. two SOL markets on different oracles), each is paired
+    let mut spot_markets: HashMap<String, Vec<_>> = HashMap::new();
+    for i in 0..state_account.number_of_spot_markets { 
+        let spot_pk = get_spot_market_public_key(i, program_id);
+        let spot_market = get_cached_spot_market(cached_accounts, &spot_pk)?;
+        spot_markets.entry(get_market_symbol(&spot_market.name)).or_default().push(spot_market);
+    }
+
+    let mut pairs = vec![];
+    for i in 0..state_account.number_of_markets { 
+        let market_pk = get_perp_market_public_key(i, program_id);
+        let mut perp_market = get_cached_perp_market(cached_accounts, &market_pk)?;
+
+        let symbol = get_market_symbol(&perp_market.name);
+        let matching_spot_markets = match spot_markets.get(&symbol) { 
+            Some(spot_markets) => spot_markets,
+            None => continue,
+        };
+
+        let (funding_apr, funding_direction) = match compute_funding_rate(connection, &mut perp_market) {
+            Ok(funding) => funding,
+            Err(e) => {
+                println!("ERROR: skipping perp {} ({}): {}", perp_market.market_index, symbol, e);
+                continue;
+            }
+        };
+
+        for spot_market in matching_spot_markets.iter() {
+            let spot_rates = compute_borrow_rate(spot_market).and_then(|borrow_rate| {
+                let borrow_apr = borrow_rate.mul(10_u128.pow(5_u32));
+                let deposit_apr = compute_deposit_rate(spot_market)?.mul(10_u128.pow(5_u32));
+                Ok((borrow_apr, deposit_apr, gross_apr(funding_apr as i128, funding_direction, borrow_apr, deposit_apr)?))
+            });
+            let (borrow_apr, deposit_apr, net_carry) = match spot_rates {
+                Ok(spot_rates) => spot_rates,
+                Err(e) => {
+                    println!("ERROR: skipping spot {} ({}): {}", spot_market.market_index, symbol, e);
+                    continue;
+                }
+            };
+
+            pairs.push(PairCarry { 
+                symbol: symbol.clone(),
+                perp_market_index: perp_market.market_index,
+                spot_market_index: spot_market.market_index,
+                funding_apr,
+                funding_direction,
+                borrow_apr,
+                deposit_apr,
+                net_carry,
+            });
+        }
+    }
+
+    pairs.sort_by(|a, b| b.net_carry.cmp(&a.net_carry));
+    Ok(pairs)
+}
+
+pub fn print_scan(pairs: &[PairCarry]) { 
+    println!(
+        "{:>4} {:<8} {:>4} {:>4} {:>12} {:>6} {:>12} {:>12} {:>12}", 
+        "rank", "symbol", "perp", "spot", "funding %", "side", "borrow %", "deposit %", "net carry %"
+    );
+    for (rank, pair) in pairs.iter().enumerate() { 
+        let side = match pair.funding_direction { 
+            PositionDirection::Long => "long",
+            PositionDirection::Short => "short",
+        };
+        println!(
+            "{:>4} {:<8} {:>4} {:>4} {:>12.4} {:>6} {:>12.4} {:>12.4} {:>12.4}",
+            rank + 1,
+            pair.symbol,
+            pair.perp_market_index,
+            pair.spot_market_index,
+            pair.funding_apr as f64 / 1e9,
+            side,
+            pair.borrow_apr as f64 / 1e9,
+            pair.deposit_apr as f64 / 1e9,
+            pair.net_carry as f64 / 1e9,
+        );
+    }
+}
@@ -0,0 +1,360 @@
+use drift::controller::position::PositionDirection;
+use drift::error::DriftResult;
+use drift::math::casting::Cast;
+use drift::math::constants::{
+    BASE_PRECISION_I128, MARGIN_PRECISION, PERCENTAGE_PRECISION, PERCENTAGE_PRECISION_I128, SPOT_WEIGHT_PRECISION,
+};
+use drift::math::orders::{standardize_base_asset_amount, standardize_base_asset_amount_ceil};
+use drift::math::safe_math::SafeMath;
+use drift::state::perp_market::PerpMarket;
+use drift::state::spot_market::SpotMarket;
+
+use crate::allocator::notional_to_base;
+use crate::margin::MarginInfo;
+
+// how big the arb is (per leg)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PositionSize {
+    // base precision (1e9)
+    Base(u64),
+    // QUOTE_PRECISION
+    Notional(u128),
+    // of free collateral (PERCENTAGE_PRECISION)
+    FreeCollateral(u128),
+}
+
+// base amount (1e9) of a position size at the oracle price (PRICE_PRECISION), rounded up to the step size
+pub fn get_base_asset_amount(
+    position_size: PositionSize,
+    oracle_price: i64,
+    free_collateral: u128,
+    step_size: u64,
+) -> DriftResult<u64> {
+    let notional = match position_size {
+        PositionSize::Base(base_asset_amount) => return Ok(base_asset_amount),
+        PositionSize::Notional(notional) => notional,
+        PositionSize::FreeCollateral(percentage) => free_collateral.safe_mul(percentage)?.safe_div(PERCENTAGE_PRECISION)?,
+    };
+
+    let base_asset_amount = notional_to_base(notional, oracle_price.max(0).unsigned_abs().cast()?)?;
+    standardize_base_asset_amount_ceil(base_asset_amount, step_size)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SizingConfig {
+    // max (perp + spot notional) / total collateral after the trade (PERCENTAGE_PRECISION, eg. 3x = 3_000_000)
+    pub max_leverage: Option<u128>,
+    // max initial margin requirement / total collateral after the trade (PERCENTAGE_PRECISION)
+    pub max_margin_utilization: u128,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeLimit {
+    MarginUtilization,
+    MaxLeverage,
+}
+
+// base precision sizes + the projected initial margin state (QUOTE_PRECISION) at the sized position
+#[derive(Debug, Clone, Copy)]
+pub struct Sizing {
+    pub requested: u64,
+    pub size: u64,
+    // what clamped the size (None = requested size fits)
+    pub limit: Option<SizeLimit>,
+    pub total_collateral: i128,
+    pub margin_requirement: i128,
+    // PERCENTAGE_PRECISION
+    pub margin_utilization: i128,
+    pub leverage: i128,
+}
+
+// per 1.0 of notional added to the arb (PERCENTAGE_PRECISION):
+//   long funding = perp long + spot borrow sold for usdc (collateral +1, requirement + perp imr + liability weight)
+//   short funding = perp short + spot bought with usdc (collateral + asset weight - 1, requirement + perp imr)
+fn get_margin_coefficients(
+    direction: PositionDirection,
+    perp_market: &PerpMarket,
+    spot_market: &SpotMarket,
+) -> DriftResult<(i128, i128)> {
+    let to_percentage = |x: u32, precision: i128| -> DriftResult<i128> {
+        x.cast::<i128>()?.safe_mul(PERCENTAGE_PRECISION_I128)?.safe_div(precision)
+    };
+    let perp_requirement = to_percentage(perp_market.margin_ratio_initial, MARGIN_PRECISION.cast()?)?;
+
+    match direction {
+        PositionDirection::Long => Ok((
+            PERCENTAGE_PRECISION_I128,
+            perp_requirement.safe_add(to_percentage(spot_market.initial_liability_weight, SPOT_WEIGHT_PRECISION.cast()?)?)?,
+        )),
+        PositionDirection::Short => Ok((
+            to_percentage(spot_market.initial_asset_weight, SPOT_WEIGHT_PRECISION.cast()?)?.safe_sub(PERCENTAGE_PRECISION_I128)?,
+            perp_requirement,
+        )),
+    }
+}
+
+// max notional (QUOTE_PRECISION) that can be added while a * (tc + n * coll) >= b + n * req
+// (None = the constraint never binds)
+fn get_max_added_notional(headroom: i128, denominator: i128) -> DriftResult<Option<i128>> {
+    if denominator <= 0 {
+        return Ok(None);
+    }
+    Ok(Some(headroom.max(0).safe_mul(PERCENTAGE_PRECISION_I128)?.safe_div(denominator)?))
+}
+
+// clamps the arb's target size (both legs, base precision) to what the account's collateral supports
+// after the trade -- current_base_asset_amount is the size already held on the target side
+#[allow(clippy::too_many_arguments)]
+pub fn size_position(
+    margin: &MarginInfo,
+    current_base_asset_amount: u64,
+    target_base_asset_amount: u64,
+    direction: PositionDirection,
+    perp_market: &PerpMarket,
+    spot_market: &SpotMarket,
+    oracle_price: i64,
+    config: &SizingConfig,
+) -> DriftResult<Sizing> {
+    let (collateral_coefficient, requirement_coefficient) = get_margin_coefficients(direction, perp_market, spot_market)?;
+    let price = oracle_price.cast::<i128>()?;
+    let total_collateral = margin.total_collateral;
+    let margin_requirement = margin.margin_requirement.cast::<i128>()?;
+    let current_notional = current_base_asset_amount.cast::<i128>()?.safe_mul(price)?.safe_div(BASE_PRECISION_I128)?;
+
+    // requirement <= utilization * collateral
+    let utilization = config.max_margin_utilization.cast::<i128>()?;
+    let max_by_utilization = get_max_added_notional(
+        utilization.safe_mul(total_collateral)?.safe_div(PERCENTAGE_PRECISION_I128)?.safe_sub(margin_requirement)?,
+        requirement_coefficient.safe_sub(utilization.safe_mul(collateral_coefficient)?.safe_div(PERCENTAGE_PRECISION_I128)?)?,
+    )?;
+
+    // 2 legs * notional <= leverage * collateral
+    let max_by_leverage = match config.max_leverage {
+        Some(leverage) => {
+            let leverage = leverage.cast::<i128>()?;
+            get_max_added_notional(
+                leverage
+                    .safe_mul(total_collateral)?
+                    .safe_div(PERCENTAGE_PRECISION_I128)?
+                    .safe_sub(current_notional.safe_mul(2)?)?,
+                PERCENTAGE_PRECISION_I128
+                    .safe_mul(2)?
+                    .safe_sub(leverage.safe_mul(collateral_coefficient)?.safe_div(PERCENTAGE_PRECISION_I128)?)?,
+            )?
+        }
+        None => None,
+    };
+
+    let mut size = target_base_asset_amount;
+    let mut limit = None;
+    if target_base_asset_amount > current_base_asset_amount && price > 0 {
+        for (max_notional, reason) in [
+            (max_by_utilization, SizeLimit::MarginUtilization),
+            (max_by_leverage, SizeLimit::MaxLeverage),
+        ] {
+            let max_notional = match max_notional {
+                Some(max_notional) => max_notional,
+                None => continue,
+            };
+            let max_size = max_notional
+                .safe_mul(BASE_PRECISION_I128)?
+                .safe_div(price)?
+                .safe_add(current_base_asset_amount.cast()?)?
+                .min(u64::MAX.cast()?)
+                .cast::<u64>()?;
+            if max_size < size {
+                // round down so the planner's ceil doesnt push it back over
+                size = standardize_base_asset_amount(max_size, perp_market.amm.order_step_size)?.max(current_base_asset_amount);
+                limit = Some(reason);
+            }
+        }
+    }
+
+    // projected state at the sized position
+    let added_notional = size
+        .saturating_sub(current_base_asset_amount)
+        .cast::<i128>()?
+        .safe_mul(price)?
+        .safe_div(BASE_PRECISION_I128)?;
+    let total_collateral = total_collateral.safe_add(
+        added_notional.safe_mul(collateral_coefficient)?.safe_div(PERCENTAGE_PRECISION_I128)?,
+    )?;
+    let margin_requirement = margin_requirement.safe_add(
+        added_notional.safe_mul(requirement_coefficient)?.safe_div(PERCENTAGE_PRECISION_I128)?,
+    )?;
+    let notional = current_notional.safe_add(added_notional)?.safe_mul(2)?;
+    let ratio = |x: i128| -> DriftResult<i128> {
+        if total_collateral <= 0 {
+            return Ok(0);
+        }
+        x.safe_mul(PERCENTAGE_PRECISION_I128)?.safe_div(total_collateral)
+    };
+
+    Ok(Sizing {
+        requested: target_base_asset_amount,
+        size,
+        limit,
+        total_collateral,
+        margin_requirement,
+        margin_utilization: ratio(margin_requirement)?,
+        leverage: ratio(notional)?,
+    })
+}
+
+pub fn print_sizing(sizing: &Sizing) {
+    let base = |x: u64| x as f64 / 1e9;
+    match sizing.limit {
+        Some(limit) => println!(
+            "SIZING: reduced {} -> {} (-{}) by {:?}",
+            base(sizing.requested),
+            base(sizing.size),
+            base(sizing.requested - sizing.size),
+            limit,
+        ),
+        None => println!("SIZING: {} fits the collateral", base(sizing.requested)),
+    }
+    println!(
+        "SIZING: after the trade -- total collateral {} initial requirement {} utilization {}% leverage {}x",
+        sizing.total_collateral as f64 / 1e6,
+        sizing.margin_requirement as f64 / 1e6,
+        sizing.margin_utilization as f64 / 1e4,
+        sizing.leverage as f64 / 1e6,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use drift::controller::position::PositionDirection::{Long, Short};
+    use drift::math::constants::{BASE_PRECISION_U64, PRICE_PRECISION_I64, QUOTE_PRECISION_I128};
+
+    use crate::test_utils;
+
+    const STEP: u64 = BASE_PRECISION_U64 / 100;
+    const ONE: u64 = BASE_PRECISION_U64;
+    const PRICE: i64 = 100 * PRICE_PRECISION_I64;
+
+    // 10% perp initial margin, 0.8 / 1.2 spot initial weights
+    fn perp_market() -> PerpMarket {
+        PerpMarket {
+            margin_ratio_initial: 1_000,
+            ..test_utils::perp_market(0, STEP)
+        }
+    }
+
+    fn spot_market() -> SpotMarket {
+        SpotMarket {
+            initial_asset_weight: 8_000,
+            initial_liability_weight: 12_000,
+            ..SpotMarket::default()
+        }
+    }
+
+    // QUOTE_PRECISION
+    fn margin(total_collateral: i128, margin_requirement: i128) -> MarginInfo {
+        MarginInfo {
+            total_collateral,
+            margin_requirement: margin_requirement as u128,
+            ..MarginInfo::default()
+        }
+    }
+
+    fn config(max_leverage: Option<u128>) -> SizingConfig {
+        SizingConfig {
+            max_leverage,
+            max_margin_utilization: 900_000,
+        }
+    }
+
+    fn size(margin: &MarginInfo, current: u64, target: u64, direction: PositionDirection, price: i64, config: &SizingConfig) -> Sizing {
+        size_position(margin, current, target, direction, &perp_market(), &spot_market(), price, config).unwrap()
+    }
+
+    #[test]
+    fn utilization_clamp_binds() {
+        // long funding: collateral +1, requirement +1.3 per 1.0 of notional
+        //   0.9 * (100 + n) = 1.3 * n -> n = 225
+        let sizing = size(&margin(100 * QUOTE_PRECISION_I128, 0), 0, 5 * ONE, Long, PRICE, &config(None));
+        assert_eq!(sizing.size, 225 * ONE / 100);
+        assert_eq!(sizing.limit, Some(SizeLimit::MarginUtilization));
+        assert_eq!(sizing.total_collateral, 325 * QUOTE_PRECISION_I128);
+        assert_eq!(sizing.margin_utilization, 900_000);
+    }
+
+    #[test]
+    fn leverage_clamp_binds() {
+        // short funding: collateral -0.2, requirement +0.1 per 1.0 of notional
+        //   2 * n = 2 * (100 - 0.2 * n) -> n = 83.33, utilization allows 321.43
+        let sizing = size(&margin(100 * QUOTE_PRECISION_I128, 0), 0, 5 * ONE, Short, PRICE, &config(Some(2_000_000)));
+        assert_eq!(sizing.size, 83 * ONE / 100);
+        assert_eq!(sizing.limit, Some(SizeLimit::MaxLeverage));
+        assert!(sizing.leverage <= 2_000_000);
+
+        // no leverage cap -> utilization binds instead
+        let sizing = size(&margin(100 * QUOTE_PRECISION_I128, 0), 0, 5 * ONE, Short, PRICE, &config(None));
+        assert_eq!(sizing.size, 321 * ONE / 100);
+        assert_eq!(sizing.limit, Some(SizeLimit::MarginUtilization));
+    }
+
+    #[test]
+    fn target_that_fits_is_unchanged() {
+        let sizing = size(&margin(100 * QUOTE_PRECISION_I128, 0), 0, ONE, Long, PRICE, &config(Some(3_000_000)));
+        assert_eq!(sizing.size, ONE);
+        assert_eq!(sizing.limit, None);
+    }
+
+    #[test]
+    fn target_at_or_below_current_passes_through() {
+        // over both limits already
+        let margin = margin(100 * QUOTE_PRECISION_I128, 95 * QUOTE_PRECISION_I128);
+        for target in [ONE, 3 * ONE] {
+            let sizing = size(&margin, 3 * ONE, target, Long, PRICE, &config(Some(1_000_000)));
+            assert_eq!(sizing.size, target);
+            assert_eq!(sizing.limit, None);
+        }
+    }
+
+    #[test]
+    fn clamp_rounds_down_to_step_but_never_below_current() {
+        // no headroom left: max size is what's held
+        let margin = margin(100 * QUOTE_PRECISION_I128, 95 * QUOTE_PRECISION_I128);
+        let sizing = size(&margin, ONE, 2 * ONE, Long, PRICE, &config(None));
+        assert_eq!(sizing.size, ONE);
+        assert_eq!(sizing.limit, Some(SizeLimit::MarginUtilization));
+
+        // held size isnt a step multiple -- rounding down would go below it
+        let current = ONE + STEP / 2;
+        let sizing = size(&margin, current, 2 * ONE, Long, PRICE, &config(None));
+        assert_eq!(sizing.size, current);
+    }
+
+    #[test]
+    fn no_price_passes_through() {
+        for price in [0, -PRICE] {
+            let sizing = size(&margin(100 * QUOTE_PRECISION_I128, 0), 0, 5 * ONE, Long, price, &config(Some(1_000_000)));
+            assert_eq!(sizing.size, 5 * ONE);
+            assert_eq!(sizing.limit, None);
+        }
+    }
+
+    #[test]
+    fn non_positive_denominator_never_binds() {
+        assert_eq!(get_max_added_notional(100, 0).unwrap(), None);
+        assert_eq!(get_max_added_notional(100, -PERCENTAGE_PRECISION_I128).unwrap(), None);
+        assert_eq!(get_max_added_notional(100, 2 * PERCENTAGE_PRECISION_I128).unwrap(), Some(50));
+        // already past the limit
+        assert_eq!(get_max_added_notional(-100, PERCENTAGE_PRECISION_I128).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn position_size_resolves_to_base() {
+        assert_eq!(get_base_asset_amount(PositionSize::Base(ONE + 1), PRICE, 0, STEP).unwrap(), ONE + 1);
+        // 150 usd at 100
+        assert_eq!(get_base_asset_amount(PositionSize::Notional(150_000_000), PRICE, 0, STEP).unwrap(), 3 * ONE / 2);
+        // 25% of 1000 usd at 100
+        assert_eq!(get_base_asset_amount(PositionSize::FreeCollateral(250_000), PRICE, 1_000_000_000, STEP).unwrap(), 5 * ONE / 2);
+        // 0.333 rounded up to the step
+        assert_eq!(get_base_asset_amount(PositionSize::Notional(100_000_000 / 3), PRICE, 0, STEP).unwrap(), 34 * STEP);
+    }
+}
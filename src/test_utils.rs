@@ -0,0 +1,49 @@
+use drift::math::constants::SPOT_CUMULATIVE_INTEREST_PRECISION;
+use drift::state::perp_market::{PerpMarket, AMM};
+use drift::state::spot_market::{SpotBalanceType, SpotMarket};
+use drift::state::user::{PerpPosition, SpotPosition, User};
+
+// perp market which only rounds orders to the step size
+pub fn perp_market(market_index: u16, order_step_size: u64) -> PerpMarket {
+    PerpMarket {
+        market_index,
+        amm: AMM {
+            order_step_size,
+            ..AMM::default()
+        },
+        ..PerpMarket::default()
+    }
+}
+
+// 9 decimals + no interest accrued so scaled balance == token amount == base amount
+pub fn spot_market(market_index: u16, order_step_size: u64) -> SpotMarket {
+    SpotMarket {
+        market_index,
+        decimals: 9,
+        order_step_size,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        ..SpotMarket::default()
+    }
+}
+
+// signed perp base / spot token amounts (negative spot = borrow), flat legs have no position
+pub fn user(perp_market: &PerpMarket, spot_market: &SpotMarket, perp: i64, spot: i64) -> User {
+    let mut user = User::default();
+    if perp != 0 {
+        user.perp_positions[0] = PerpPosition {
+            market_index: perp_market.market_index,
+            base_asset_amount: perp,
+            ..PerpPosition::default()
+        };
+    }
+    if spot != 0 {
+        user.spot_positions[0] = SpotPosition {
+            market_index: spot_market.market_index,
+            scaled_balance: spot.unsigned_abs(),
+            balance_type: if spot > 0 { SpotBalanceType::Deposit } else { SpotBalanceType::Borrow },
+            ..SpotPosition::default()
+        };
+    }
+    user
+}
@@ -0,0 +1,91 @@
+use anchor_client::anchor_lang::{InstructionData, ToAccountMetas};
+use anchor_client::solana_client::rpc_client::RpcClient;
+use anchor_client::solana_sdk::instruction::{AccountMeta, Instruction, InstructionError};
+use anchor_client::solana_sdk::pubkey::Pubkey;
+use anchor_client::solana_sdk::signature::{Signature, Signer};
+use anchor_client::solana_sdk::transaction::{Transaction, TransactionError};
+
+use crate::constants::*;
+use crate::error_codes::describe_error_code;
+
+pub struct SimulationReport { 
+    pub err: Option<TransactionError>,
+    pub logs: Vec<String>,
+    pub units_consumed: Option<u64>,
+}
+
+impl SimulationReport { 
+    // custom program error (ie. drift's ErrorCode) of the failing ix if there is one
+    pub fn error_code(&self) -> Option<u32> { 
+        match &self.err { 
+            Some(TransactionError::InstructionError(_, InstructionError::Custom(code))) => Some(*code),
+            _ => None,
+        }
+    }
+}
+
+pub fn build_instruction(
+    program_id: &Pubkey,
+    accounts: impl ToAccountMetas, 
+    args: impl InstructionData, 
+    remaining_accounts: &[AccountMeta],
+) -> Instruction { 
+    let mut account_metas = accounts.to_account_metas(None);
+    account_metas.extend_from_slice(remaining_accounts);
+
+    Instruction { 
+        program_id: *program_id, 
+        accounts: account_metas, 
+        data: args.data(),
+    }
+}
+
+fn build_transaction(connection: &RpcClient, signer: &dyn Signer, ixs: &[Instruction]) -> Result<Transaction> { 
+    let blockhash = connection.get_latest_blockhash()?;
+    let signers: Vec<&dyn Signer> = vec![signer];
+    let tx = Transaction::new_signed_with_payer(
+        ixs, 
+        Some(&signer.pubkey()), 
+        &signers, 
+        blockhash
+    );
+    Ok(tx)
+}
+
+pub fn simulate_transaction(connection: &RpcClient, signer: &dyn Signer, ixs: &[Instruction]) -> Result<SimulationReport> { 
+    let tx = build_transaction(connection, signer, ixs)?;
+    let result = connection.simulate_transaction(&tx)?.value;
+
+    Ok(SimulationReport { 
+        err: result.err, 
+        logs: result.logs.unwrap_or_default(), 
+        units_consumed: result.units_consumed,
+    })
+}
+
+pub fn send_transaction(connection: &RpcClient, signer: &dyn Signer, ixs: &[Instruction]) -> Result<Signature> { 
+    let tx = build_transaction(connection, signer, ixs)?;
+    let sig = connection.send_and_confirm_transaction(&tx).map_err(|e| { 
+        // preflight/confirmation failed on a program error (ie. drift's ErrorCode)
+        match e.get_transaction_error() { 
+            Some(TransactionError::InstructionError(_, InstructionError::Custom(code))) => DriftError::OrderRejected(code),
+            _ => DriftError::RpcError(e),
+        }
+    })?;
+    Ok(sig)
+}
+
+pub fn print_simulation(label: &str, report: &SimulationReport) { 
+    for log in report.logs.iter() { 
+        println!("{}: [log] {}", label, log);
+    }
+    match report.units_consumed { 
+        Some(units) => println!("{}: compute units consumed: {}", label, units), 
+        None => println!("{}: compute units consumed: unknown", label),
+    }
+    match (&report.err, report.error_code()) { 
+        (None, _) => println!("{}: simulation succeeded", label),
+        (Some(_), Some(code)) => println!("{}: simulation failed with {}", label, describe_error_code(code)),
+        (Some(err), None) => println!("{}: simulation failed: {}", label, err),
+    }
+}
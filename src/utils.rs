@@ -20,39 +20,54 @@ macro_rules! cast {
     ($target: expr, $pat: path) => {
         {
             if let $pat(a) = $target { // #1
-                a
+                Ok(a)
             } else {
-                panic!(
-                    "mismatch variant when cast to {}", 
-                    stringify!($pat)); // #2
+                Err($crate::constants::DriftError::MarketTypeMismatch(
+                    stringify!($pat))) // #2
             }
         }
     };
 }
 
-pub fn get_cached_accounts(connection: &RpcClient, state_account: &State) -> Result<HashMap<Pubkey, Market>> { 
+pub fn get_cached_perp_market(cached_accounts: &HashMap<Pubkey, Market>, address: &Pubkey) -> Result<PerpMarket> {
+    let market = cached_accounts.get(address).ok_or(DriftError::MarketNotFound(*address))?;
+    Ok(*cast!(market, Market::PerpMarket)?)
+}
+
+pub fn get_cached_spot_market(cached_accounts: &HashMap<Pubkey, Market>, address: &Pubkey) -> Result<SpotMarket> {
+    let market = cached_accounts.get(address).ok_or(DriftError::MarketNotFound(*address))?;
+    Ok(*cast!(market, Market::SpotMarket)?)
+}
+
+// "SOL-PERP" -> "SOL", "SOL" -> "SOL"
+pub fn get_market_symbol(name: &[u8]) -> String { 
+    let name = String::from_utf8_lossy(name);
+    name.trim().split('-').next().unwrap_or_default().to_string()
+}
+
+pub fn get_cached_accounts(connection: &RpcClient, state_account: &State, program_id: &Pubkey) -> Result<HashMap<Pubkey, Market>> { 
     let mut cached_accounts: HashMap<Pubkey, Market> = HashMap::new();
     for i in 0..state_account.number_of_markets { 
-        let market_pk = get_perp_market_public_key(i, &PROGRAM_ID);
+        let market_pk = get_perp_market_public_key(i, program_id);
         let market = get_perp_market(connection, &market_pk)?;
         cached_accounts.insert(market_pk, Market::PerpMarket(market));
     }
 
     for i in 0..state_account.number_of_spot_markets { 
-        let spot_pk = get_spot_market_public_key(i, &PROGRAM_ID);
+        let spot_pk = get_spot_market_public_key(i, program_id);
         let spot_market = get_spot_market(connection, &spot_pk)?;
         cached_accounts.insert(spot_pk, Market::SpotMarket(spot_market));
     }
     Ok(cached_accounts)
 }
 
-pub fn get_remaining_accounts(state_account: &State, cached_accounts: &HashMap<Pubkey, Market>) -> Result<Vec<AccountMeta>> { 
+pub fn get_remaining_accounts(state_account: &State, cached_accounts: &HashMap<Pubkey, Market>, program_id: &Pubkey) -> Result<Vec<AccountMeta>> { 
     let mut perp_market_dict = HashMap::new();
     let mut spot_market_dict = HashMap::new();
     let mut oracle_dict = HashMap::new();
 
     for i in 0..state_account.number_of_markets { 
-        let market_pk = get_perp_market_public_key(i, &PROGRAM_ID);
+        let market_pk = get_perp_market_public_key(i, program_id);
 
         let market_meta = AccountMeta {
             pubkey: market_pk, 
@@ -61,7 +76,7 @@ pub fn get_remaining_accounts(state_account: &State, cached_accounts: &HashMap<P
         };
         perp_market_dict.insert(market_pk, market_meta);
 
-        let market = cast!(cached_accounts.get(&market_pk).unwrap(), Market::PerpMarket);
+        let market = get_cached_perp_market(cached_accounts, &market_pk)?;
         let oracle_meta = AccountMeta { 
             pubkey: market.amm.oracle, 
             is_signer: false, 
@@ -71,7 +86,7 @@ pub fn get_remaining_accounts(state_account: &State, cached_accounts: &HashMap<P
     }
 
     for i in 0..state_account.number_of_spot_markets { 
-        let spot_pk = get_spot_market_public_key(i, &PROGRAM_ID);
+        let spot_pk = get_spot_market_public_key(i, program_id);
         let spot_meta = AccountMeta { 
             pubkey: spot_pk, 
             is_signer: false, 
@@ -80,7 +95,7 @@ pub fn get_remaining_accounts(state_account: &State, cached_accounts: &HashMap<P
         spot_market_dict.insert(spot_pk, spot_meta);
 
         if i != 0 {
-            let spot_market = cast!(cached_accounts.get(&spot_pk).unwrap(), Market::SpotMarket);
+            let spot_market = get_cached_spot_market(cached_accounts, &spot_pk)?;
             let oracle_meta = AccountMeta { 
                 pubkey: spot_market.oracle, 
                 is_signer: false, 